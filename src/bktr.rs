@@ -0,0 +1,215 @@
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom};
+use crate::util::{Aes128CtrReader, ReadSeek, Shared, read_u32_le, read_u64_le};
+
+// Patch (update) sections are described by two bucket trees: a relocation one mapping the patched section to base/patch data,
+// and a subsection one giving the AES-CTR counter generation of each region of the patch data itself
+
+pub const BUCKET_TREE_MAGIC: u32 = u32::from_le_bytes(*b"BKTR");
+
+// Both the header node and every bucket are this size
+pub const BUCKET_TREE_NODE_SIZE: usize = 0x4000;
+
+const BUCKET_TREE_NODE_HEADER_SIZE: usize = 0x10;
+const RELOCATION_ENTRY_SIZE: usize = 0x14;
+const SUBSECTION_ENTRY_SIZE: usize = 0x10;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct RelocationEntry {
+    pub virtual_offset: u64,
+    pub physical_offset: u64,
+    // 0 is the base section, 1 the patch section
+    pub storage_index: u32
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct SubsectionEntry {
+    pub offset: u64,
+    pub generation: u32
+}
+
+// Layout: header node (reserved u32, bucket count u32, end offset u64, bucket start offsets) followed by the buckets (reserved u32, entry count u32, end offset u64, entries)
+fn parse_bucket_tree<T, F: Fn(&[u8]) -> T>(table: &[u8], expected_entry_count: usize, entry_size: usize, parse_entry: F) -> Result<(Vec<T>, u64)> {
+    if table.len() < BUCKET_TREE_NODE_HEADER_SIZE {
+        return Err(Error::new(ErrorKind::InvalidData, "Bucket tree table is too small"));
+    }
+
+    let bucket_count = read_u32_le(table, 0x4) as usize;
+    let end_offset = read_u64_le(table, 0x8);
+    if bucket_count.checked_add(1).and_then(|node_count| node_count.checked_mul(BUCKET_TREE_NODE_SIZE)).is_none_or(|tree_size| tree_size > table.len()) {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Bucket tree has too many buckets ({}) for its table size ({:#X})", bucket_count, table.len())));
+    }
+
+    let max_bucket_entry_count = (BUCKET_TREE_NODE_SIZE - BUCKET_TREE_NODE_HEADER_SIZE) / entry_size;
+    let mut entries: Vec<T> = Vec::with_capacity(expected_entry_count);
+    for bucket in table[BUCKET_TREE_NODE_SIZE..].chunks_exact(BUCKET_TREE_NODE_SIZE).take(bucket_count) {
+        let bucket_entry_count = read_u32_le(bucket, 0x4) as usize;
+        if bucket_entry_count > max_bucket_entry_count {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Bucket tree bucket has too many entries ({})", bucket_entry_count)));
+        }
+
+        entries.extend(bucket[BUCKET_TREE_NODE_HEADER_SIZE..].chunks_exact(entry_size).take(bucket_entry_count).map(&parse_entry));
+    }
+
+    if entries.len() != expected_entry_count {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Bucket tree entry count mismatch (found {}, expected {})", entries.len(), expected_entry_count)));
+    }
+
+    Ok((entries, end_offset))
+}
+
+// Returns the entries along with the size of the patched section
+pub fn parse_relocation_table(table: &[u8], entry_count: usize) -> Result<(Vec<RelocationEntry>, u64)> {
+    let (entries, virtual_size) = parse_bucket_tree(table, entry_count, RELOCATION_ENTRY_SIZE, |entry_data| RelocationEntry {
+        virtual_offset: read_u64_le(entry_data, 0),
+        physical_offset: read_u64_le(entry_data, 0x8),
+        storage_index: read_u32_le(entry_data, 0x10)
+    })?;
+
+    if entries.first().is_some_and(|entry| entry.virtual_offset != 0) || entries.windows(2).any(|entry_pair| entry_pair[0].virtual_offset >= entry_pair[1].virtual_offset) {
+        return Err(Error::new(ErrorKind::InvalidData, "Relocation entries are not sorted or do not start at offset 0"));
+    }
+
+    Ok((entries, virtual_size))
+}
+
+pub fn parse_subsection_table(table: &[u8], entry_count: usize) -> Result<(Vec<SubsectionEntry>, u64)> {
+    let (entries, physical_size) = parse_bucket_tree(table, entry_count, SUBSECTION_ENTRY_SIZE, |entry_data| SubsectionEntry {
+        offset: read_u64_le(entry_data, 0),
+        generation: read_u32_le(entry_data, 0xC)
+    })?;
+
+    if entries.first().is_some_and(|entry| entry.offset != 0) || entries.windows(2).any(|entry_pair| entry_pair[0].offset >= entry_pair[1].offset) {
+        return Err(Error::new(ErrorKind::InvalidData, "Subsection entries are not sorted or do not start at offset 0"));
+    }
+
+    Ok((entries, physical_size))
+}
+
+pub struct IndirectReader {
+    storages: Vec<Shared<dyn ReadSeek>>,
+    entries: Vec<RelocationEntry>,
+    size: u64,
+    offset: u64
+}
+
+impl IndirectReader {
+    pub fn new(storages: Vec<Shared<dyn ReadSeek>>, entries: Vec<RelocationEntry>, size: u64) -> Self {
+        Self {
+            storages,
+            entries,
+            size,
+            offset: 0
+        }
+    }
+}
+
+impl Read for IndirectReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if (self.offset >= self.size) || buf.is_empty() {
+            return Ok(0);
+        }
+
+        // Reads never go past the end of the entry containing the current offset
+        let entry_idx = self.entries.partition_point(|entry| entry.virtual_offset <= self.offset);
+        if entry_idx == 0 {
+            return Err(Error::new(ErrorKind::InvalidData, format!("No relocation entry for offset {:#X}", self.offset)));
+        }
+        let entry = self.entries[entry_idx - 1];
+        let entry_end_offset = self.entries.get(entry_idx).map(|next_entry| next_entry.virtual_offset).unwrap_or(self.size);
+
+        let storage = match self.storages.get(entry.storage_index as usize) {
+            Some(storage) => storage,
+            None => return Err(Error::new(ErrorKind::InvalidData, format!("Invalid relocation storage index {}", entry.storage_index)))
+        };
+
+        let read_len = std::cmp::min(buf.len() as u64, entry_end_offset - self.offset) as usize;
+        let mut storage = storage.lock().unwrap();
+        storage.seek(SeekFrom::Start(entry.physical_offset + (self.offset - entry.virtual_offset)))?;
+        let read_size = storage.read(&mut buf[..read_len])?;
+        self.offset += read_size as u64;
+        Ok(read_size)
+    }
+}
+
+impl Seek for IndirectReader {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let new_offset = match pos {
+            SeekFrom::Start(start_pos) => Some(start_pos),
+            SeekFrom::Current(cur_pos) => self.offset.checked_add_signed(cur_pos),
+            SeekFrom::End(end_pos) => self.size.checked_add_signed(end_pos)
+        };
+
+        match new_offset {
+            Some(new_offset) => {
+                self.offset = new_offset;
+                Ok(self.offset)
+            },
+            None => Err(Error::new(ErrorKind::InvalidInput, "Invalid seek to a negative or overflowing position"))
+        }
+    }
+}
+
+pub struct Aes128CtrExReader {
+    size: u64,
+    offset: u64,
+    ctr: u64,
+    subsections: Vec<SubsectionEntry>,
+    // Reused for every subsection (only its counter changes), so that the key isn't expanded on each read
+    ctr_reader: Aes128CtrReader
+}
+
+impl Aes128CtrExReader {
+    // Like Aes128CtrReader, the base offset is absolute (since it is part of the counter)
+    pub fn new(base_reader: Shared<dyn ReadSeek>, base_offset: u64, size: u64, ctr: u64, subsections: Vec<SubsectionEntry>, key: Vec<u8>) -> Self {
+        Self {
+            ctr_reader: Aes128CtrReader::new(base_reader, base_offset, size, ctr, key),
+            size,
+            offset: 0,
+            ctr,
+            subsections
+        }
+    }
+}
+
+impl Read for Aes128CtrExReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if (self.offset >= self.size) || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let subsection_idx = self.subsections.partition_point(|subsection| subsection.offset <= self.offset);
+        if subsection_idx == 0 {
+            return Err(Error::new(ErrorKind::InvalidData, format!("No subsection entry for offset {:#X}", self.offset)));
+        }
+        let subsection = self.subsections[subsection_idx - 1];
+        let subsection_end_offset = self.subsections.get(subsection_idx).map(|next_subsection| next_subsection.offset).unwrap_or(self.size);
+
+        // The subsection's generation replaces the lower half of the section counter
+        let ctr = (self.ctr & 0xFFFFFFFF00000000) | subsection.generation as u64;
+        self.ctr_reader.set_ctr(ctr);
+        self.ctr_reader.seek(SeekFrom::Start(self.offset))?;
+
+        let read_len = std::cmp::min(buf.len() as u64, subsection_end_offset - self.offset) as usize;
+        let read_size = self.ctr_reader.read(&mut buf[..read_len])?;
+        self.offset += read_size as u64;
+        Ok(read_size)
+    }
+}
+
+impl Seek for Aes128CtrExReader {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let new_offset = match pos {
+            SeekFrom::Start(start_pos) => Some(start_pos),
+            SeekFrom::Current(cur_pos) => self.offset.checked_add_signed(cur_pos),
+            SeekFrom::End(end_pos) => self.size.checked_add_signed(end_pos)
+        };
+
+        match new_offset {
+            Some(new_offset) => {
+                self.offset = new_offset;
+                Ok(self.offset)
+            },
+            None => Err(Error::new(ErrorKind::InvalidInput, "Invalid seek to a negative or overflowing position"))
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use std::io::{Error, ErrorKind, Result};
+use std::mem::offset_of;
+use crate::util::{ReadSeek, Shared, ValidateRaw, check_raw_enum, reader_read_at, reader_read_val_at, reader_read_validated_val_at};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum ContentMetaType {
+    Unknown = 0x0,
+    SystemProgram = 0x1,
+    SystemData = 0x2,
+    SystemUpdate = 0x3,
+    BootImagePackage = 0x4,
+    BootImagePackageSafe = 0x5,
+    Application = 0x80,
+    Patch = 0x81,
+    AddOnContent = 0x82,
+    Delta = 0x83,
+    DataPatch = 0x84
+}
+
+impl TryFrom<u8> for ContentMetaType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0x0 => Ok(Self::Unknown),
+            0x1 => Ok(Self::SystemProgram),
+            0x2 => Ok(Self::SystemData),
+            0x3 => Ok(Self::SystemUpdate),
+            0x4 => Ok(Self::BootImagePackage),
+            0x5 => Ok(Self::BootImagePackageSafe),
+            0x80 => Ok(Self::Application),
+            0x81 => Ok(Self::Patch),
+            0x82 => Ok(Self::AddOnContent),
+            0x83 => Ok(Self::Delta),
+            0x84 => Ok(Self::DataPatch),
+            _ => Err(Error::new(ErrorKind::InvalidData, format!("Invalid content meta type value: {:#X}", value)))
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum ContentType {
+    Meta,
+    Program,
+    Data,
+    Control,
+    HtmlDocument,
+    LegalInformation,
+    DeltaFragment
+}
+
+impl TryFrom<u8> for ContentType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(Self::Meta),
+            1 => Ok(Self::Program),
+            2 => Ok(Self::Data),
+            3 => Ok(Self::Control),
+            4 => Ok(Self::HtmlDocument),
+            5 => Ok(Self::LegalInformation),
+            6 => Ok(Self::DeltaFragment),
+            _ => Err(Error::new(ErrorKind::InvalidData, format!("Invalid content type value: {:#X}", value)))
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(C)]
+pub struct Header {
+    pub title_id: u64,
+    pub version: u32,
+    pub meta_type: ContentMetaType,
+    pub reserved: u8,
+    pub extended_header_size: u16,
+    pub content_count: u16,
+    pub content_meta_count: u16,
+    pub attributes: u8,
+    pub reserved_1: [u8; 0x3],
+    pub required_download_system_version: u32,
+    pub reserved_2: [u8; 0x4]
+}
+
+impl ValidateRaw for Header {
+    fn validate_raw(data: &[u8]) -> Result<()> {
+        check_raw_enum::<ContentMetaType>(data, offset_of!(Header, meta_type))
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(C)]
+pub struct ContentInfo {
+    pub hash: [u8; 0x20],
+    pub content_id: [u8; 0x10],
+    pub size: [u8; 0x6],
+    pub content_type: ContentType,
+    pub id_offset: u8
+}
+
+impl ValidateRaw for ContentInfo {
+    fn validate_raw(data: &[u8]) -> Result<()> {
+        check_raw_enum::<ContentType>(data, offset_of!(ContentInfo, content_type))
+    }
+}
+
+impl ContentInfo {
+    pub fn get_size(&self) -> u64 {
+        let mut size_bytes = [0u8; 0x8];
+        size_bytes[..self.size.len()].copy_from_slice(&self.size);
+        u64::from_le_bytes(size_bytes)
+    }
+
+    #[inline]
+    pub fn get_content_id_string(&self) -> String {
+        hex::encode(self.content_id)
+    }
+
+    // Name of the content's NCA as found in NSPs or installed content directories
+    pub fn get_file_name(&self) -> String {
+        match self.content_type {
+            ContentType::Meta => format!("{}.cnmt.nca", self.get_content_id_string()),
+            _ => format!("{}.nca", self.get_content_id_string())
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(C)]
+pub struct ContentMetaInfo {
+    pub title_id: u64,
+    pub version: u32,
+    pub meta_type: ContentMetaType,
+    pub attributes: u8,
+    pub reserved: [u8; 0x2]
+}
+
+impl ValidateRaw for ContentMetaInfo {
+    fn validate_raw(data: &[u8]) -> Result<()> {
+        check_raw_enum::<ContentMetaType>(data, offset_of!(ContentMetaInfo, meta_type))
+    }
+}
+
+pub struct Cnmt {
+    pub header: Header,
+    pub extended_header: Vec<u8>,
+    pub contents: Vec<ContentInfo>,
+    pub content_metas: Vec<ContentMetaInfo>,
+    pub digest: [u8; 0x20]
+}
+
+impl Cnmt {
+    pub fn new(reader: Shared<dyn ReadSeek>) -> Result<Self> {
+        let header: Header = reader_read_validated_val_at(&reader, 0)?;
+        let mut offset = std::mem::size_of::<Header>() as u64;
+
+        let mut extended_header = vec![0u8; header.extended_header_size as usize];
+        reader_read_at(&reader, offset, &mut extended_header)?;
+        offset += extended_header.len() as u64;
+
+        let mut contents: Vec<ContentInfo> = Vec::with_capacity(header.content_count as usize);
+        for _ in 0..header.content_count {
+            let content: ContentInfo = reader_read_validated_val_at(&reader, offset)?;
+            contents.push(content);
+            offset += std::mem::size_of::<ContentInfo>() as u64;
+        }
+
+        let mut content_metas: Vec<ContentMetaInfo> = Vec::with_capacity(header.content_meta_count as usize);
+        for _ in 0..header.content_meta_count {
+            let content_meta: ContentMetaInfo = reader_read_validated_val_at(&reader, offset)?;
+            content_metas.push(content_meta);
+            offset += std::mem::size_of::<ContentMetaInfo>() as u64;
+        }
+
+        let digest: [u8; 0x20] = reader_read_val_at(&reader, offset)?;
+
+        Ok(Self {
+            header,
+            extended_header,
+            contents,
+            content_metas,
+            digest
+        })
+    }
+
+    pub fn find_content(&self, content_type: ContentType) -> Option<&ContentInfo> {
+        self.contents.iter().find(|content| content.content_type == content_type)
+    }
+
+    // Some types (like Data or DeltaFragment) may have several contents
+    pub fn find_contents(&self, content_type: ContentType) -> impl Iterator<Item = &ContentInfo> {
+        self.contents.iter().filter(move |content| content.content_type == content_type)
+    }
+}
\ No newline at end of file
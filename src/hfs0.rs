@@ -0,0 +1,143 @@
+use std::io::{Error, ErrorKind, Result, SeekFrom};
+use sha2::{Digest, Sha256};
+use crate::util::{DecodeMode, PlainData, ReadSeek, Shared, SubReader, decode_name, reader_read_at, reader_read_val_at};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+#[repr(C)]
+pub struct Header {
+    pub magic: u32,
+    pub file_count: u32,
+    pub string_table_size: u32,
+    pub reserved: [u8; 0x4]
+}
+
+unsafe impl PlainData for Header {}
+
+impl Header {
+    pub const MAGIC: u32 = u32::from_le_bytes(*b"HFS0");
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+#[repr(C)]
+pub struct FileEntry {
+    pub offset: u64,
+    pub size: usize,
+    pub string_table_offset: u32,
+    pub hashed_region_size: u32,
+    pub reserved: [u8; 0x8],
+    pub hash: [u8; 0x20]
+}
+
+unsafe impl PlainData for FileEntry {}
+
+pub struct HFS0 {
+    reader: Shared<dyn ReadSeek>,
+    header: Header,
+    file_entries: Vec<FileEntry>,
+    string_table: Vec<u8>,
+    decode_mode: DecodeMode
+}
+
+impl HFS0 {
+    #[inline]
+    pub fn new(reader: Shared<dyn ReadSeek>) -> Result<Self> {
+        Self::new_with_options(reader, DecodeMode::default())
+    }
+
+    pub fn new_with_options(reader: Shared<dyn ReadSeek>, decode_mode: DecodeMode) -> Result<Self> {
+        let header: Header = reader_read_val_at(&reader, 0)?;
+        if header.magic != Header::MAGIC {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid HFS0 magic"));
+        }
+
+        let mut file_entries: Vec<FileEntry> = Vec::with_capacity(header.file_count as usize);
+
+        for i in 0..header.file_count as usize {
+            let file_entry: FileEntry = reader_read_val_at(&reader, (std::mem::size_of::<Header>() + i * std::mem::size_of::<FileEntry>()) as u64)?;
+            file_entries.push(file_entry);
+        }
+
+        let mut str_table = vec![0u8; header.string_table_size as usize];
+        reader_read_at(&reader, (std::mem::size_of::<Header>() + file_entries.len() * std::mem::size_of::<FileEntry>()) as u64, &mut str_table)?;
+
+        Ok(Self {
+            reader,
+            header,
+            file_entries,
+            string_table: str_table,
+            decode_mode
+        })
+    }
+
+    pub fn list_files(&self) -> Result<Vec<String>> {
+        let mut file_names: Vec<String> = Vec::with_capacity(self.file_entries.len());
+
+        for entry in self.file_entries.iter() {
+            // Out-of-bounds offsets (crafted images) just give empty names, like with PFS0
+            let str_t = self.string_table.get(entry.string_table_offset as usize..).unwrap_or(&[]);
+            let name_len = str_t.iter().position(|chr| *chr == 0).unwrap_or(str_t.len());
+
+            file_names.push(decode_name(str_t[..name_len].to_vec(), self.decode_mode)?);
+        }
+
+        Ok(file_names)
+    }
+
+    pub fn get_file_size(&mut self, idx: usize) -> Result<usize> {
+        if idx >= self.file_entries.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid file index"));
+        }
+
+        Ok(self.file_entries[idx].size)
+    }
+
+    pub fn read_file(&mut self, idx: usize, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        if idx >= self.file_entries.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid file index"));
+        }
+
+        let entry = &self.file_entries[idx];
+        if offset.checked_add(buf.len()).is_none_or(|read_end_offset| read_end_offset > entry.size) {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "EOF reached"));
+        }
+
+        let read_offset = self.get_file_data_offset(idx) + offset as u64;
+
+        let mut reader = self.reader.lock().unwrap();
+        reader.seek(SeekFrom::Start(read_offset))?;
+        reader.read_exact(buf)?;
+        Ok(buf.len())
+    }
+
+    fn get_file_data_offset(&self, idx: usize) -> u64 {
+        let base_offset = std::mem::size_of::<Header>() + std::mem::size_of::<FileEntry>() * self.header.file_count as usize + self.header.string_table_size as usize;
+        base_offset as u64 + self.file_entries[idx].offset
+    }
+
+    pub fn open_file(&mut self, idx: usize) -> Result<SubReader> {
+        if idx >= self.file_entries.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid file index"));
+        }
+
+        Ok(SubReader::new(self.reader.clone(), self.get_file_data_offset(idx), self.file_entries[idx].size as u64))
+    }
+
+    // Only the first hashed_region_size bytes of each file are hashed (for partitions this covers their HFS0 header), entries without a hashed region are considered valid
+    pub fn verify_file(&mut self, idx: usize) -> Result<bool> {
+        if idx >= self.file_entries.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid file index"));
+        }
+
+        let entry = self.file_entries[idx];
+        if entry.hashed_region_size == 0 {
+            return Ok(true);
+        }
+        if entry.hashed_region_size as usize > entry.size {
+            return Ok(false);
+        }
+
+        let mut hashed_region = vec![0u8; entry.hashed_region_size as usize];
+        self.read_file(idx, 0, &mut hashed_region)?;
+        Ok(Sha256::digest(&hashed_region).as_slice() == entry.hash)
+    }
+}
@@ -1,18 +1,32 @@
+use std::collections::BTreeMap;
 use std::io::{BufRead, BufReader, Error, ErrorKind, Result};
+use aes::Aes128;
+use block_modes::{BlockMode, Ecb};
+use block_modes::block_padding::NoPadding;
 use hex::FromHex;
+use crate::nca::{KeyArea, KeyAreaEncryptionKeyIndex};
 use crate::util::ReadSeek;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct Keyset {
     pub header_key: [u8; 0x20],
     pub key_area_keys_application: Vec<[u8; 0x10]>,
     pub key_area_keys_ocean: Vec<[u8; 0x10]>,
     pub key_area_keys_system: Vec<[u8; 0x10]>,
-    pub title_key_encryption_keys: Vec<[u8; 0x10]>
+    pub title_key_encryption_keys: Vec<[u8; 0x10]>,
+    pub master_keys: Vec<[u8; 0x10]>,
+    // Sources are only kept around, keys are never derived from them (derived keys are expected to be present too)
+    pub title_kek_source: [u8; 0x10],
+    pub aes_kek_generation_source: [u8; 0x10],
+    pub aes_key_generation_source: [u8; 0x10],
+    pub key_area_key_application_source: [u8; 0x10],
+    pub key_area_key_ocean_source: [u8; 0x10],
+    pub key_area_key_system_source: [u8; 0x10],
+    pub title_keys: BTreeMap<[u8; 0x10], [u8; 0x10]>
 }
 
 impl Keyset {
-    fn get_key_name_idx(base_name: &str, name: &String) -> Option<usize> {
+    fn get_key_name_idx(base_name: &str, name: &str) -> Option<usize> {
         if name.starts_with(base_name) && (name.len() == base_name.len() + 2) {
             let idx_str = &name[name.len() - 2..];
             u8::from_str_radix(idx_str, 16).ok().map(|s| s as usize)
@@ -22,65 +36,134 @@ impl Keyset {
         }
     }
 
+    fn to_key<const N: usize>(name: &str, key_data: &[u8]) -> Result<[u8; N]> {
+        key_data.try_into().map_err(|_| Error::new(ErrorKind::InvalidData, format!("Invalid size for key '{}' (expected {:#X} bytes, got {:#X})", name, N, key_data.len())))
+    }
+
+    // Keys files may list indices out of order or with gaps, missing ones are left zeroed
+    fn set_indexed_key(keys: &mut Vec<[u8; 0x10]>, idx: usize, key: [u8; 0x10]) {
+        if idx >= keys.len() {
+            keys.resize(idx + 1, [0; 0x10]);
+        }
+
+        keys[idx] = key;
+    }
+
     pub fn from<R: ReadSeek>(reader: R) -> Result<Self> {
         let lines = BufReader::new(reader).lines();
 
-        let mut keyset = Keyset {
-            header_key: [0; 0x20],
-            key_area_keys_application: Vec::new(),
-            key_area_keys_ocean: Vec::new(),
-            key_area_keys_system: Vec::new(),
-            title_key_encryption_keys: Vec::new()
-        };
-
-        for line in lines {
-            if let Ok(line_str) = line {
-                let items: Vec<_> = line_str.split("=").collect();
-                if items.len() != 2 {
-                    return Err(Error::new(ErrorKind::InvalidInput, "Invalid keyset key-value"));
-                }
-
-                let mut key = String::from(items[0]);
-                key.retain(|c| !c.is_whitespace());
-                let mut value = String::from(items[1]);
-                value.retain(|c| !c.is_whitespace());
-
-                let key_data = Vec::from_hex(value).expect("Invalid hex key");
-
-                if key.eq("header_key") {
-                    keyset.header_key = key_data.clone().try_into().unwrap();
-                }
-                else if let Some(idx) = Self::get_key_name_idx("key_area_key_application_", &key) {
-                    if idx >= keyset.key_area_keys_application.len() {
-                        keyset.key_area_keys_application.resize(idx, [0; 0x10]);
-                    }
-
-                    keyset.key_area_keys_application.insert(idx, key_data.clone().try_into().unwrap());
-                }
-                else if let Some(idx) = Self::get_key_name_idx("key_area_key_ocean_", &key) {
-                    if idx >= keyset.key_area_keys_ocean.len() {
-                        keyset.key_area_keys_ocean.resize(idx, [0; 0x10]);
-                    }
-
-                    keyset.key_area_keys_ocean.insert(idx, key_data.clone().try_into().unwrap());
-                }
-                else if let Some(idx) = Self::get_key_name_idx("key_area_key_system_", &key) {
-                    if idx >= keyset.key_area_keys_system.len() {
-                        keyset.key_area_keys_system.resize(idx, [0; 0x10]);
-                    }
-
-                    keyset.key_area_keys_system.insert(idx, key_data.clone().try_into().unwrap());
-                }
-                else if let Some(idx) = Self::get_key_name_idx("titlekek_", &key) {
-                    if idx >= keyset.title_key_encryption_keys.len() {
-                        keyset.title_key_encryption_keys.resize(idx, [0; 0x10]);
-                    }
-
-                    keyset.title_key_encryption_keys.insert(idx, key_data.clone().try_into().unwrap());
-                }
+        let mut keyset = Keyset::default();
+
+        for (line_idx, line_str) in lines.enumerate() {
+            let line_str = line_str?;
+
+            // Hand-edited keys files often have blank lines or comments
+            let line = line_str.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            let (key, value) = match line.split_once('=') {
+                Some(key_value) => key_value,
+                None => return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid keyset key-value at line {}", line_idx + 1)))
+            };
+
+            let mut key = String::from(key);
+            key.retain(|c| !c.is_whitespace());
+            let mut value = String::from(value);
+            value.retain(|c| !c.is_whitespace());
+
+            // Some keys files prefix values with "0x"
+            let value = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")).unwrap_or(&value);
+
+            let key_data = match Vec::from_hex(value) {
+                Ok(key_data) => key_data,
+                Err(err) => return Err(Error::new(ErrorKind::InvalidData, format!("Invalid hex value for key '{}' at line {}: {}", key, line_idx + 1, err)))
+            };
+
+            if key.eq("header_key") {
+                keyset.header_key = Self::to_key(&key, &key_data)?;
+            }
+            else if let Some(idx) = Self::get_key_name_idx("key_area_key_application_", &key) {
+                Self::set_indexed_key(&mut keyset.key_area_keys_application, idx, Self::to_key(&key, &key_data)?);
+            }
+            else if let Some(idx) = Self::get_key_name_idx("key_area_key_ocean_", &key) {
+                Self::set_indexed_key(&mut keyset.key_area_keys_ocean, idx, Self::to_key(&key, &key_data)?);
+            }
+            else if let Some(idx) = Self::get_key_name_idx("key_area_key_system_", &key) {
+                Self::set_indexed_key(&mut keyset.key_area_keys_system, idx, Self::to_key(&key, &key_data)?);
+            }
+            else if let Some(idx) = Self::get_key_name_idx("titlekek_", &key) {
+                Self::set_indexed_key(&mut keyset.title_key_encryption_keys, idx, Self::to_key(&key, &key_data)?);
+            }
+            else if let Some(idx) = Self::get_key_name_idx("master_key_", &key) {
+                Self::set_indexed_key(&mut keyset.master_keys, idx, Self::to_key(&key, &key_data)?);
+            }
+            else if let Some(source_key) = match key.as_str() {
+                "titlekek_source" => Some(&mut keyset.title_kek_source),
+                "aes_kek_generation_source" => Some(&mut keyset.aes_kek_generation_source),
+                "aes_key_generation_source" => Some(&mut keyset.aes_key_generation_source),
+                "key_area_key_application_source" => Some(&mut keyset.key_area_key_application_source),
+                "key_area_key_ocean_source" => Some(&mut keyset.key_area_key_ocean_source),
+                "key_area_key_system_source" => Some(&mut keyset.key_area_key_system_source),
+                _ => None
+            } {
+                *source_key = Self::to_key(&key, &key_data)?;
             }
         }
 
         Ok(keyset)
     }
-}
\ No newline at end of file
+
+    // Generations missing from keys files are left zeroed when later ones are present, hence they are skipped here
+    pub fn available_generations(&self) -> Vec<u8> {
+        self.key_area_keys_application.iter().enumerate().filter(|(_, key_area_key)| **key_area_key != [0; 0x10]).map(|(generation, _)| generation as u8).collect()
+    }
+
+    // Title keys are registered encrypted (as found in tickets), mapped by rights ID
+    #[inline]
+    pub fn register_title_key(&mut self, rights_id: [u8; 0x10], title_key: [u8; 0x10]) {
+        self.title_keys.insert(rights_id, title_key);
+    }
+
+    pub fn register_title_keys<I: IntoIterator<Item = ([u8; 0x10], [u8; 0x10])>>(&mut self, title_keys: I) {
+        self.title_keys.extend(title_keys);
+    }
+
+    #[inline]
+    pub fn get_title_key(&self, rights_id: &[u8; 0x10]) -> Option<[u8; 0x10]> {
+        self.title_keys.get(rights_id).copied()
+    }
+}
+
+fn get_key_area_key(keyset: &Keyset, index: KeyAreaEncryptionKeyIndex, generation: u8) -> Result<[u8; 0x10]> {
+    let key_area_keys = match index {
+        KeyAreaEncryptionKeyIndex::Application => &keyset.key_area_keys_application,
+        KeyAreaEncryptionKeyIndex::Ocean => &keyset.key_area_keys_ocean,
+        KeyAreaEncryptionKeyIndex::System => &keyset.key_area_keys_system
+    };
+    match key_area_keys.get(generation as usize) {
+        Some(key_area_key) => Ok(*key_area_key),
+        None => Err(Error::new(ErrorKind::InvalidInput, format!("Key area key of kind {:?} (key_area_key_*_*) not present for key generation {}", index, generation)))
+    }
+}
+
+// The generation is the already adjusted one (see nca::Header::get_key_generation)
+pub fn decrypt_key_area(encrypted: &[u8; 0x40], keyset: &Keyset, index: KeyAreaEncryptionKeyIndex, generation: u8) -> Result<KeyArea> {
+    let key_area_key = get_key_area_key(keyset, index, generation)?;
+
+    let mut key_area_buf = *encrypted;
+    let key_area_ecb_iv = [0; 0x10];
+    let key_area_ecb = Ecb::<Aes128, NoPadding>::new_var(&key_area_key, &key_area_ecb_iv).unwrap();
+    Ok(KeyArea::from_slice(key_area_ecb.decrypt(&mut key_area_buf).unwrap()))
+}
+
+pub fn encrypt_key_area(key_area: &KeyArea, keyset: &Keyset, index: KeyAreaEncryptionKeyIndex, generation: u8) -> Result<[u8; 0x40]> {
+    let key_area_key = get_key_area_key(keyset, index, generation)?;
+
+    let mut key_area_buf: [u8; 0x40] = key_area.as_slice().try_into().unwrap();
+    let key_area_ecb_iv = [0; 0x10];
+    let key_area_ecb = Ecb::<Aes128, NoPadding>::new_var(&key_area_key, &key_area_ecb_iv).unwrap();
+    key_area_ecb.encrypt(&mut key_area_buf, 0x40).unwrap();
+    Ok(key_area_buf)
+}
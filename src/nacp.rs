@@ -0,0 +1,99 @@
+use std::io::Result;
+use crate::util::{ReadSeek, Shared, read_nul_terminated_str, read_u64_le, reader_read_at};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum Language {
+    AmericanEnglish,
+    BritishEnglish,
+    Japanese,
+    French,
+    German,
+    LatinAmericanSpanish,
+    Spanish,
+    Italian,
+    Dutch,
+    CanadianFrench,
+    Portuguese,
+    Russian,
+    Korean,
+    TraditionalChinese,
+    SimplifiedChinese,
+    BrazilianPortuguese
+}
+
+pub const LANGUAGE_COUNT: usize = 16;
+pub const NACP_SIZE: usize = 0x4000;
+
+const APPLICATION_TITLE_SIZE: usize = 0x300;
+const APPLICATION_TITLE_NAME_SIZE: usize = 0x200;
+const SUPPORTED_LANGUAGE_FLAG_OFFSET: usize = 0x302C;
+const PRESENCE_GROUP_ID_OFFSET: usize = 0x3038;
+const DISPLAY_VERSION_OFFSET: usize = 0x3060;
+const DISPLAY_VERSION_SIZE: usize = 0x10;
+const SAVE_DATA_OWNER_ID_OFFSET: usize = 0x3078;
+
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct ApplicationTitle {
+    pub name: String,
+    pub publisher: String
+}
+
+impl ApplicationTitle {
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.name.is_empty()
+    }
+}
+
+pub struct Nacp {
+    pub titles: Vec<ApplicationTitle>,
+    pub supported_language_flag: u32,
+    pub presence_group_id: u64,
+    pub display_version: String,
+    pub save_data_owner_id: u64
+}
+
+impl Nacp {
+    pub fn new(reader: Shared<dyn ReadSeek>) -> Result<Self> {
+        let mut data = vec![0u8; NACP_SIZE];
+        reader_read_at(&reader, 0, &mut data)?;
+
+        let titles = data.chunks_exact(APPLICATION_TITLE_SIZE).take(LANGUAGE_COUNT).map(|title_data| ApplicationTitle {
+            name: read_nul_terminated_str(&title_data[..APPLICATION_TITLE_NAME_SIZE]),
+            publisher: read_nul_terminated_str(&title_data[APPLICATION_TITLE_NAME_SIZE..])
+        }).collect();
+
+        Ok(Self {
+            titles,
+            supported_language_flag: u32::from_le_bytes(data[SUPPORTED_LANGUAGE_FLAG_OFFSET..SUPPORTED_LANGUAGE_FLAG_OFFSET + 4].try_into().unwrap()),
+            presence_group_id: read_u64_le(&data, PRESENCE_GROUP_ID_OFFSET),
+            display_version: read_nul_terminated_str(&data[DISPLAY_VERSION_OFFSET..DISPLAY_VERSION_OFFSET + DISPLAY_VERSION_SIZE]),
+            save_data_owner_id: read_u64_le(&data, SAVE_DATA_OWNER_ID_OFFSET)
+        })
+    }
+
+    // Falls back to the first non-empty title if the one for the requested language is empty
+    pub fn get_title(&self, language: Language) -> Option<&ApplicationTitle> {
+        match self.titles.get(language as usize) {
+            Some(title) if !title.is_empty() => Some(title),
+            _ => self.titles.iter().find(|title| !title.is_empty())
+        }
+    }
+
+    #[inline]
+    pub fn get_name(&self, language: Language) -> Option<&str> {
+        self.get_title(language).map(|title| title.name.as_str())
+    }
+
+    #[inline]
+    pub fn get_publisher(&self, language: Language) -> Option<&str> {
+        self.get_title(language).map(|title| title.publisher.as_str())
+    }
+
+    // NACPs don't store the application ID itself, but the presence group ID is the application's own ID for virtually every title
+    #[inline]
+    pub fn get_application_id(&self) -> u64 {
+        self.presence_group_id
+    }
+}
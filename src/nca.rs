@@ -1,14 +1,23 @@
-use std::io::{Error, ErrorKind, Result};
+use std::fs::{File, read_dir};
+use std::io::{Cursor, Error, ErrorKind, Read, Result, Write};
+use std::mem::offset_of;
+use std::path::{Path, PathBuf};
 use aes::Aes128;
 use aes::NewBlockCipher;
 use block_modes::Ecb;
 use block_modes::BlockMode;
 use block_modes::block_padding::NoPadding;
+use rsa::{BigUint, Pss, RsaPublicKey};
+use sha2::{Digest, Sha256};
 use xts_mode::Xts128;
-use crate::key::Keyset;
-use crate::pfs0::PFS0;
-use crate::romfs::RomFs;
-use crate::util::{Aes128CtrReader, ReadSeek, Shared, get_nintendo_tweak, new_shared};
+use crate::bktr::{Aes128CtrExReader, IndirectReader, parse_relocation_table, parse_subsection_table, BUCKET_TREE_MAGIC};
+use crate::cnmt::Cnmt;
+use crate::key::{Keyset, decrypt_key_area, encrypt_key_area};
+use crate::nacp::{Language, Nacp};
+use crate::npdm::Npdm;
+use crate::pfs0::{PFS0, Sha256HashInfo, Sha256VerifiedReader, verify_with_sha256};
+use crate::romfs::{IvfcHashInfo, IvfcLevelInfo, IvfcVerifiedReader, RomFs, verify_with_ivfc};
+use crate::util::{Aes128CtrReader, Aes128XtsReader, DEFAULT_COPY_CHUNK_SIZE, DataReader, PlainReader, ReadSeek, Shared, SubReader, ValidateRaw, check_raw_enum, copy_ranged, get_nintendo_tweak, new_shared, read_validated_val, reader_read_at};
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 #[repr(u8)]
@@ -17,6 +26,18 @@ pub enum DistributionType {
     Gamecard
 }
 
+impl TryFrom<u8> for DistributionType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(Self::System),
+            1 => Ok(Self::Gamecard),
+            _ => Err(Error::new(ErrorKind::InvalidData, format!("Invalid distribution type value: {:#X}", value)))
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 #[repr(u8)]
 pub enum ContentType {
@@ -28,6 +49,22 @@ pub enum ContentType {
     PublicData
 }
 
+impl TryFrom<u8> for ContentType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(Self::Program),
+            1 => Ok(Self::Meta),
+            2 => Ok(Self::Control),
+            3 => Ok(Self::Manual),
+            4 => Ok(Self::Data),
+            5 => Ok(Self::PublicData),
+            _ => Err(Error::new(ErrorKind::InvalidData, format!("Invalid content type value: {:#X}", value)))
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
 #[repr(C)]
 pub struct RSASignature {
@@ -41,6 +78,14 @@ pub struct RSASignature {
     part_8: [u8; 0x20]
 }
 
+impl RSASignature {
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(self as *const _ as *const u8, std::mem::size_of::<Self>())
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
 #[repr(C)]
 pub struct SdkAddonVersion {
@@ -58,6 +103,24 @@ pub struct FileSystemEntry {
     reserved: [u8; 0x8]
 }
 
+// All section offsets/sizes are stored in media units, and converted only here
+impl FileSystemEntry {
+    #[inline]
+    pub fn get_start_offset(&self) -> u64 {
+        self.start_offset as u64 * MEDIA_UNIT_SIZE as u64
+    }
+
+    #[inline]
+    pub fn get_end_offset(&self) -> u64 {
+        self.end_offset as u64 * MEDIA_UNIT_SIZE as u64
+    }
+
+    #[inline]
+    pub fn get_size(&self) -> u64 {
+        self.get_end_offset().saturating_sub(self.get_start_offset())
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
 #[repr(C)]
 pub struct Sha256Hash {
@@ -72,6 +135,19 @@ pub enum KeyAreaEncryptionKeyIndex {
     System
 }
 
+impl TryFrom<u8> for KeyAreaEncryptionKeyIndex {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(Self::Application),
+            1 => Ok(Self::Ocean),
+            2 => Ok(Self::System),
+            _ => Err(Error::new(ErrorKind::InvalidData, format!("Invalid key area encryption key index value: {:#X}", value)))
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
 #[repr(C)]
 pub struct KeyArea {
@@ -97,6 +173,16 @@ impl KeyArea {
         }
     }
 
+    #[inline]
+    pub fn get_aes_xts_key(&self) -> [u8; 0x20] {
+        self.aes_xts_key
+    }
+
+    #[inline]
+    pub fn get_aes_ctr_key(&self) -> [u8; 0x10] {
+        self.aes_ctr_key
+    }
+
     pub fn as_slice(&self) -> &[u8] {
         unsafe {
             std::slice::from_raw_parts(self as *const _ as *const u8, std::mem::size_of::<Self>())
@@ -110,9 +196,17 @@ impl KeyArea {
     }
 }
 
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Version {
+    NCA0,
+    NCA2,
+    NCA3
+}
+
 pub const MAX_FILESYSTEM_COUNT: usize = 4;
 pub const SECTOR_SIZE: usize = 0x200;
 pub const MEDIA_UNIT_SIZE: usize = 0x200;
+pub const HEADER_SIGNATURE_PUBLIC_EXPONENT: u32 = 0x10001;
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 #[repr(C)]
@@ -143,8 +237,47 @@ pub struct Header {
     pub reserved_6: [u8; 0x20]
 }
 
+impl ValidateRaw for Header {
+    fn validate_raw(data: &[u8]) -> Result<()> {
+        check_raw_enum::<DistributionType>(data, offset_of!(Header, dist_type))?;
+        check_raw_enum::<ContentType>(data, offset_of!(Header, cnt_type))?;
+        check_raw_enum::<KeyAreaEncryptionKeyIndex>(data, offset_of!(Header, key_area_encryption_key_index))
+    }
+}
+
 impl Header {
     pub const MAGIC: u32 = u32::from_le_bytes(*b"NCA3");
+    pub const MAGIC_NCA2: u32 = u32::from_le_bytes(*b"NCA2");
+    pub const MAGIC_NCA0: u32 = u32::from_le_bytes(*b"NCA0");
+
+    #[inline]
+    pub fn get_version(&self) -> Option<Version> {
+        Self::get_version_for_magic(self.magic)
+    }
+
+    pub fn get_version_for_magic(magic: u32) -> Option<Version> {
+        match magic {
+            Self::MAGIC => Some(Version::NCA3),
+            Self::MAGIC_NCA2 => Some(Version::NCA2),
+            Self::MAGIC_NCA0 => Some(Version::NCA0),
+            _ => None
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(self as *const _ as *const u8, std::mem::size_of::<Self>())
+        }
+    }
+
+    // Both signatures cover the (decrypted) header right after them
+    pub fn get_signed_region(&self) -> &[u8] {
+        &self.as_slice()[2 * std::mem::size_of::<RSASignature>()..]
+    }
+
+    pub fn encrypted_key_area_bytes(&self) -> [u8; 0x40] {
+        self.encrypted_key_area.as_slice().try_into().unwrap()
+    }
 
     #[inline]
     pub fn get_key_generation(self) -> u8 {
@@ -174,6 +307,18 @@ pub enum FileSystemType {
     PartitionFs
 }
 
+impl TryFrom<u8> for FileSystemType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(Self::RomFs),
+            1 => Ok(Self::PartitionFs),
+            _ => Err(Error::new(ErrorKind::InvalidData, format!("Invalid filesystem type value: {:#X}", value)))
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 #[repr(u8)]
 pub enum HashType {
@@ -182,6 +327,19 @@ pub enum HashType {
     HierarchicalIntegrity = 3
 }
 
+impl TryFrom<u8> for HashType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(Self::Auto),
+            2 => Ok(Self::HierarchicalSha256),
+            3 => Ok(Self::HierarchicalIntegrity),
+            _ => Err(Error::new(ErrorKind::InvalidData, format!("Invalid hash type value: {:#X}", value)))
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 #[repr(u8)]
 pub enum EncryptionType {
@@ -192,6 +350,31 @@ pub enum EncryptionType {
     AesCtrEx
 }
 
+impl TryFrom<u8> for EncryptionType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(Self::Auto),
+            1 => Ok(Self::None),
+            2 => Ok(Self::AesCtrOld),
+            3 => Ok(Self::AesCtr),
+            4 => Ok(Self::AesCtrEx),
+            _ => Err(Error::new(ErrorKind::InvalidData, format!("Invalid encryption type value: {:#X}", value)))
+        }
+    }
+}
+
+// These must be kept in sync with the types actually handled when opening filesystems
+
+pub fn supported_encryption_types() -> &'static [EncryptionType] {
+    &[EncryptionType::None, EncryptionType::AesCtr, EncryptionType::AesCtrEx]
+}
+
+pub fn supported_fs_types() -> &'static [FileSystemType] {
+    &[FileSystemType::RomFs, FileSystemType::PartitionFs]
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 #[repr(C)]
 pub struct HierarchicalSha256 {
@@ -210,6 +393,19 @@ pub struct HierarchicalSha256 {
     reserved_6: [u8; 0x10]
 }
 
+impl HierarchicalSha256 {
+    pub fn get_hash_info(&self) -> Sha256HashInfo {
+        Sha256HashInfo {
+            master_hash: self.hash_table_hash.hash,
+            block_size: self.block_size as usize,
+            hash_table_offset: self.hash_table_offset,
+            hash_table_size: self.hash_table_size,
+            data_offset: self.pfs0_offset,
+            data_size: self.pfs0_size
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 #[repr(C)]
 pub struct HierarchicalIntegrityLevelInfo {
@@ -233,6 +429,17 @@ pub struct HierarchicalIntegrity {
 
 impl HierarchicalIntegrity {
     pub const MAGIC: u32 = u32::from_le_bytes(*b"IVFC");
+
+    pub fn get_hash_info(&self) -> IvfcHashInfo {
+        IvfcHashInfo {
+            master_hash: self.hash.hash,
+            levels: self.levels.iter().map(|level| IvfcLevelInfo {
+                offset: level.offset,
+                size: level.size,
+                block_size: 1usize.checked_shl(level.block_size_log2).unwrap_or(0)
+            }).collect()
+        }
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -242,15 +449,31 @@ pub union HashInfo {
     hierarchical_integrity: HierarchicalIntegrity
 }
 
+// Safe view of the HashInfo union, with the variant selected by the section's hash type
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum SectionHashInfo {
+    HierarchicalSha256(Sha256HashInfo),
+    HierarchicalIntegrity(IvfcHashInfo)
+}
+
+impl SectionHashInfo {
+    pub fn get_master_hash(&self) -> [u8; 0x20] {
+        match self {
+            Self::HierarchicalSha256(hash_info) => hash_info.master_hash,
+            Self::HierarchicalIntegrity(hash_info) => hash_info.master_hash
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 #[repr(C)]
 pub struct BucketRelocationInfo {
     offset: u64,
     size: usize,
     magic: u32,
-    unk_1: u32,
-    unk_2: i32,
-    unk_3: u32
+    version: u32,
+    entry_count: u32,
+    reserved: u32
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
@@ -296,94 +519,274 @@ pub struct FileSystemHeader {
     reserved_5: [u8; 0x8]
 }
 
+impl ValidateRaw for FileSystemHeader {
+    fn validate_raw(data: &[u8]) -> Result<()> {
+        check_raw_enum::<FileSystemType>(data, offset_of!(FileSystemHeader, fs_type))?;
+        check_raw_enum::<HashType>(data, offset_of!(FileSystemHeader, hash_type))?;
+        check_raw_enum::<EncryptionType>(data, offset_of!(FileSystemHeader, encryption_type))
+    }
+}
+
+impl FileSystemHeader {
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(self as *const _ as *const u8, std::mem::size_of::<Self>())
+        }
+    }
+
+    // The only place where the union is accessed, always following a hash type
+    fn read_hash_info(&self, hash_type: HashType) -> Option<SectionHashInfo> {
+        match hash_type {
+            HashType::HierarchicalSha256 => Some(SectionHashInfo::HierarchicalSha256(unsafe { self.hash_info.hierarchical_sha256 }.get_hash_info())),
+            HashType::HierarchicalIntegrity => Some(SectionHashInfo::HierarchicalIntegrity(unsafe { self.hash_info.hierarchical_integrity }.get_hash_info())),
+            HashType::Auto => None
+        }
+    }
+
+    #[inline]
+    pub fn get_hash_info(&self) -> Option<SectionHashInfo> {
+        self.read_hash_info(self.hash_type)
+    }
+
+    #[inline]
+    pub fn get_master_hash(&self) -> Option<[u8; 0x20]> {
+        self.get_hash_info().map(|hash_info| hash_info.get_master_hash())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SectionDiffKind {
+    Identical,
+    Changed,
+    Added,
+    Removed
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct SectionDiff {
+    pub idx: usize,
+    pub kind: SectionDiffKind
+}
+
+// Sections are matched by FS entry slot (which is what idx is here), and only considered identical if their master hashes match
+// FS headers are checked first, since a master hash from a corrupted one would be meaningless
+pub fn diff_sections(base: &mut NCA, update: &mut NCA) -> Result<Vec<SectionDiff>> {
+    let mut diffs: Vec<SectionDiff> = Vec::new();
+    for slot in 0..MAX_FILESYSTEM_COUNT {
+        let base_idx = base.fs_header_slots.iter().position(|base_slot| *base_slot == slot);
+        let update_idx = update.fs_header_slots.iter().position(|update_slot| *update_slot == slot);
+
+        let kind = match (base_idx, update_idx) {
+            (Some(base_idx), Some(update_idx)) => {
+                base.check_fs_header(base_idx)?;
+                update.check_fs_header(update_idx)?;
+
+                let base_fs_header = &base.fs_headers[base_idx];
+                let update_fs_header = &update.fs_headers[update_idx];
+                let base_master_hash = base_fs_header.get_master_hash();
+                let is_identical = base_fs_header.fs_type == update_fs_header.fs_type && base_master_hash.is_some() && base_master_hash == update_fs_header.get_master_hash();
+                if is_identical {
+                    SectionDiffKind::Identical
+                }
+                else {
+                    SectionDiffKind::Changed
+                }
+            },
+            (None, Some(_)) => SectionDiffKind::Added,
+            (Some(_), None) => SectionDiffKind::Removed,
+            (None, None) => continue
+        };
+
+        diffs.push(SectionDiff { idx: slot, kind });
+    }
+
+    Ok(diffs)
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct SectionVerifyResult {
+    pub idx: usize,
+    pub fs_header_valid: bool,
+    pub hash_tree_valid: bool
+}
+
+impl SectionVerifyResult {
+    #[inline]
+    pub fn is_valid(&self) -> bool {
+        self.fs_header_valid && self.hash_tree_valid
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct VerifyReport {
+    // None if the header signature was not checked
+    pub header_signature_valid: Option<bool>,
+    pub sections: Vec<SectionVerifyResult>
+}
+
+impl VerifyReport {
+    pub fn is_valid(&self) -> bool {
+        (self.header_signature_valid != Some(false)) && self.sections.iter().all(|section| section.is_valid())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SectionRole {
+    ExeFs,
+    RomFs,
+    Logo,
+    Meta
+}
+
+pub enum FileSystem {
+    PartitionFs(PFS0),
+    RomFs(RomFs)
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct NcaControlInfo {
+    pub name: String,
+    pub author: String,
+    pub display_version: String,
+    pub application_id: u64
+}
+
 pub struct NCA {
     reader: Shared<dyn ReadSeek>,
-    dec_key_area: KeyArea,
+    keyset: Keyset,
+    title_key: Option<[u8; 0x10]>,
+    dec_key_area: Option<KeyArea>,
     dec_title_key: Option<[u8; 0x10]>,
+    fs_header_slots: Vec<usize>,
     pub header: Header,
     pub fs_headers: Vec<FileSystemHeader>
 }
 
 impl NCA {
     pub fn new(reader: Shared<dyn ReadSeek>, keyset: &Keyset, title_key: Option<[u8; 0x10]>) -> Result<Self> {
+        let mut nca = Self::new_header_only(reader, keyset)?;
+        nca.title_key = title_key;
+        nca.decrypt_keys()?;
+
+        Ok(nca)
+    }
+
+    pub fn new_header_only(reader: Shared<dyn ReadSeek>, keyset: &Keyset) -> Result<Self> {
+        if keyset.header_key == [0; 0x20] {
+            return Err(Error::new(ErrorKind::InvalidInput, "Header key (header_key) not present in keyset"));
+        }
+
         let cipher_1 = Aes128::new_varkey(&keyset.header_key[..0x10]).unwrap();
         let cipher_2 = Aes128::new_varkey(&keyset.header_key[0x10..]).unwrap();
         let xts = Xts128::new(cipher_1, cipher_2);
 
-        let mut header: Header = unsafe {
-            std::mem::zeroed()
+        let mut header_buf = vec![0u8; std::mem::size_of::<Header>()];
+        // Section offsets are absolute, so the header is always at the start
+        reader_read_at(&reader, 0, &mut header_buf)?;
+        xts.decrypt_area(&mut header_buf, SECTOR_SIZE, 0, get_nintendo_tweak);
+
+        // The magic is checked before anything else, since with a wrong header key everything else is garbage too
+        let magic = u32::from_le_bytes(header_buf[offset_of!(Header, magic)..offset_of!(Header, magic) + 4].try_into().unwrap());
+        let version = match Header::get_version_for_magic(magic) {
+            Some(version) => version,
+            // A garbage magic usually means the header key is wrong rather than an unsupported NCA
+            None => return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid NCA magic {:?} (only NCA0, NCA2 and NCA3 are supported, or the header key may be wrong)", String::from_utf8_lossy(&magic.to_le_bytes()))))
         };
-        let header_buf = unsafe {
-            std::slice::from_raw_parts_mut(&mut header as *mut _ as *mut u8, std::mem::size_of::<Header>())
+        let header: Header = read_validated_val(&header_buf)?;
+
+        let mut fs_headers_buf = vec![0u8; std::mem::size_of::<FileSystemHeader>() * MAX_FILESYSTEM_COUNT];
+        reader_read_at(&reader, std::mem::size_of::<Header>() as u64, &mut fs_headers_buf)?;
+        match version {
+            // NCA3 encrypts the FS headers right after the header (sectors 2 to 5)
+            Version::NCA3 => xts.decrypt_area(&mut fs_headers_buf, SECTOR_SIZE, 2, get_nintendo_tweak),
+            // NCA2 (and NCA0) encrypt each FS header on its own, always as sector 0
+            Version::NCA2 | Version::NCA0 => {
+                for fs_header_buf in fs_headers_buf.chunks_exact_mut(std::mem::size_of::<FileSystemHeader>()) {
+                    xts.decrypt_area(fs_header_buf, SECTOR_SIZE, 0, get_nintendo_tweak);
+                }
+            }
         };
-        reader.lock().unwrap().read_exact(header_buf)?;
-        xts.decrypt_area(header_buf, SECTOR_SIZE, 0, get_nintendo_tweak);
 
-        if header.magic != Header::MAGIC {
-            return Err(Error::new(ErrorKind::InvalidInput, "Invalid NCA magic (only NCA3 is supported for now)"));
+        let mut actual_fs_headers: Vec<FileSystemHeader> = Vec::new();
+        let mut fs_header_slots: Vec<usize> = Vec::new();
+        for (slot, (fs_entry, fs_header_buf)) in header.fs_entries.iter().zip(fs_headers_buf.chunks_exact(std::mem::size_of::<FileSystemHeader>())).enumerate() {
+            if fs_entry.get_start_offset() > 0 {
+                // Only save non-empty/present filesystem headers (unused ones aren't validated), remembering which FS entry each one belongs to
+                let fs_header: FileSystemHeader = read_validated_val(fs_header_buf).map_err(|err| Error::new(err.kind(), format!("Invalid FS header {}: {}", slot, err)))?;
+                actual_fs_headers.push(fs_header);
+                fs_header_slots.push(slot);
+            }
         }
-    
-        let mut fs_headers: [FileSystemHeader; MAX_FILESYSTEM_COUNT] = [unsafe { std::mem::zeroed() }; MAX_FILESYSTEM_COUNT];
-        let fs_headers_buf = unsafe {
-            std::slice::from_raw_parts_mut(fs_headers.as_mut_ptr() as *mut u8, std::mem::size_of::<FileSystemHeader>() * fs_headers.len())
-        };
-        reader.lock().unwrap().read_exact(fs_headers_buf)?;
-        xts.decrypt_area(fs_headers_buf, SECTOR_SIZE, 2, get_nintendo_tweak);
-
-        let key_gen = header.get_key_generation();
-        let key_area_keys = match header.key_area_encryption_key_index {
-            KeyAreaEncryptionKeyIndex::Application => &keyset.key_area_keys_application,
-            KeyAreaEncryptionKeyIndex::Ocean => &keyset.key_area_keys_ocean,
-            KeyAreaEncryptionKeyIndex::System => &keyset.key_area_keys_system
-        };
-        if key_gen as usize >= key_area_keys.len() {
-            return Err(Error::new(ErrorKind::InvalidInput, format!("Key area key of kind {:?} (key_area_key_*_*) not present for key generation {}", header.key_area_encryption_key_index, key_gen)));
+
+        Ok(Self {
+            reader,
+            keyset: keyset.clone(),
+            title_key: None,
+            dec_key_area: None,
+            dec_title_key: None,
+            fs_header_slots,
+            header,
+            fs_headers: actual_fs_headers
+        })
+    }
+
+    #[inline]
+    pub fn from_bytes(data: Vec<u8>, keyset: &Keyset, title_key: Option<[u8; 0x10]>) -> Result<Self> {
+        Self::new(new_shared(Cursor::new(data)), keyset, title_key)
+    }
+
+    // Like the one given to new(), the title key is the encrypted one (as found in tickets), and takes precedence over the keyset ones
+    pub fn set_title_key(&mut self, title_key: &[u8; 0x10]) -> Result<()> {
+        self.title_key = Some(*title_key);
+        self.dec_key_area = None;
+        self.dec_title_key = None;
+        self.decrypt_keys()
+    }
+
+    fn decrypt_keys(&mut self) -> Result<()> {
+        if self.dec_key_area.is_some() {
+            return Ok(());
         }
-        let key_area_key = &key_area_keys[key_gen as usize];
+
+        let key_gen = self.header.get_key_generation();
 
         let mut dec_key_area = KeyArea::empty();
         let mut dec_title_key: Option<[u8; 0x10]> = None;
 
-        if header.rights_id != [0; 0x10] {
-            if let Some(mut enc_title_key) = title_key {
-                if key_gen as usize >= keyset.title_key_encryption_keys.len() {
+        if self.needs_title_key_crypto() {
+            // An explicitly supplied title key takes precedence over the ones registered in the keyset
+            if let Some(mut enc_title_key) = self.title_key.or_else(|| self.keyset.get_title_key(&self.header.rights_id)) {
+                if key_gen as usize >= self.keyset.title_key_encryption_keys.len() {
                     return Err(Error::new(ErrorKind::InvalidInput, format!("Title key encryption key (titlekek_*) not present for key generation {}", key_gen)));
                 }
 
-                let title_key_encryption_key = keyset.title_key_encryption_keys[key_gen as usize];
+                let title_key_encryption_key = self.keyset.title_key_encryption_keys[key_gen as usize];
                 let title_key_ecb_iv = [0; 0x10];
                 let title_key_ecb = Ecb::<Aes128, NoPadding>::new_var(&title_key_encryption_key, &title_key_ecb_iv).unwrap();
                 dec_title_key = Some(title_key_ecb.decrypt(&mut enc_title_key).unwrap().try_into().unwrap());
             }
             else {
-                return Err(Error::new(ErrorKind::InvalidInput, format!("NCA requires title key to be decrypted and none was supplied")));
+                return Err(Error::new(ErrorKind::InvalidInput, "NCA requires title key to be decrypted and none was supplied"));
             }
         }
         else {
-            let dec_key_area_ecb_iv = get_nintendo_tweak(0);
-            let dec_key_area_ecb = Ecb::<Aes128, NoPadding>::new_var(key_area_key, &dec_key_area_ecb_iv).unwrap();
-            dec_key_area = KeyArea::from_slice(dec_key_area_ecb.decrypt(header.encrypted_key_area.as_mut_slice()).unwrap());
+            // Decrypts a separate copy, the header must keep holding the encrypted key area
+            dec_key_area = decrypt_key_area(&self.header.encrypted_key_area_bytes(), &self.keyset, self.header.key_area_encryption_key_index, key_gen)?;
         }
 
-        let mut actual_fs_headers: Vec<FileSystemHeader> = Vec::new();
-        for i in 0..MAX_FILESYSTEM_COUNT {
-            let fs_entry = header.fs_entries[i];
-            let fs_header = fs_headers[i];
+        self.dec_key_area = Some(dec_key_area);
+        self.dec_title_key = dec_title_key;
+        Ok(())
+    }
 
-            let fs_start_offset = fs_entry.start_offset as u64 * MEDIA_UNIT_SIZE as u64;
-            if fs_start_offset > 0 {
-                // Only save non-empty/present filesystem headers
-                actual_fs_headers.push(fs_header);
-            }
-        }
+    #[inline]
+    pub fn content_size(&self) -> u64 {
+        self.header.cnt_size as u64
+    }
 
-        Ok(Self {
-            reader: reader,
-            dec_key_area: dec_key_area,
-            dec_title_key: dec_title_key,
-            header: header,
-            fs_headers: actual_fs_headers
-        })
+    #[inline]
+    pub fn media_unit_size(&self) -> usize {
+        MEDIA_UNIT_SIZE
     }
 
     #[inline]
@@ -391,54 +794,325 @@ impl NCA {
         self.fs_headers.len()
     }
 
-    pub fn get_aes_ctr_decrypt_key(&self) -> Vec<u8> {
+    pub fn get_aes_ctr_decrypt_key(&mut self) -> Result<Vec<u8>> {
+        self.decrypt_keys()?;
+
         if let Some(dec_title_key) = self.dec_title_key {
-            dec_title_key.to_vec()
+            Ok(dec_title_key.to_vec())
         }
         else {
-            self.dec_key_area.aes_ctr_key.to_vec()
+            Ok(self.dec_key_area.unwrap().aes_ctr_key.to_vec())
+        }
+    }
+
+    // Title key crypto NCAs don't use their key area, so it's only decrypted here (with the keyset's key area keys)
+    pub fn get_decrypted_key_area(&mut self) -> Result<KeyArea> {
+        if self.needs_title_key_crypto() {
+            return decrypt_key_area(&self.header.encrypted_key_area_bytes(), &self.keyset, self.header.key_area_encryption_key_index, self.header.get_key_generation());
+        }
+
+        self.decrypt_keys()?;
+        Ok(self.dec_key_area.unwrap())
+    }
+
+    // The (decrypted) key a section is actually AES-CTR decrypted with, either the title key or the key area's one
+    pub fn get_section_ctr_key(&mut self, idx: usize) -> Result<[u8; 0x10]> {
+        if idx >= self.fs_headers.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid filesystem index"));
         }
+
+        let fs_header = self.fs_headers[idx];
+        let is_nca0_romfs = (self.header.get_version() == Some(Version::NCA0)) && (fs_header.fs_type == FileSystemType::RomFs);
+        if is_nca0_romfs || !matches!(fs_header.encryption_type, EncryptionType::AesCtr | EncryptionType::AesCtrEx) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Section {} is not AES-CTR encrypted (encryption type: {:?})", idx, fs_header.encryption_type)));
+        }
+
+        Ok(self.get_aes_ctr_decrypt_key()?.try_into().unwrap())
     }
 
-    fn get_fs_offset(&self, idx: usize) -> u64 {
+    fn get_fs_offset(&self, idx: usize) -> Result<u64> {
         let fs_header = &self.fs_headers[idx];
-        let fs_entry = &self.header.fs_entries[idx];
+        let fs_entry = &self.header.fs_entries[self.fs_header_slots[idx]];
 
         if fs_header.sparse_info.generation != 0 {
-            todo!("Sparse section NCA support")
+            Err(Error::new(ErrorKind::Unsupported, format!("Sparse sections are not supported (section {})", idx)))
         }
         else {
-            fs_entry.start_offset as u64 * MEDIA_UNIT_SIZE as u64
+            Ok(fs_entry.get_start_offset())
         }
     }
 
+    fn get_fs_size(&self, idx: usize) -> u64 {
+        self.header.fs_entries[self.fs_header_slots[idx]].get_size()
+    }
+
+    pub fn is_fs_header_valid(&self, idx: usize) -> bool {
+        Sha256::digest(self.fs_headers[idx].as_slice()).as_slice() == self.header.fs_header_hashes[self.fs_header_slots[idx]].hash
+    }
+
+    // A mismatch usually means a corrupted NCA, so filesystems aren't opened over garbage data
+    fn check_fs_header(&self, idx: usize) -> Result<()> {
+        match self.is_fs_header_valid(idx) {
+            true => Ok(()),
+            false => Err(Error::new(ErrorKind::InvalidData, format!("FS header hash mismatch for section {} (FS entry slot {})", idx, self.fs_header_slots[idx])))
+        }
+    }
+
+    pub fn verify_fs_headers(&self) -> Result<bool> {
+        Ok((0..self.fs_headers.len()).all(|idx| self.is_fs_header_valid(idx)))
+    }
+
+    pub fn verify_section(&mut self, idx: usize) -> Result<bool> {
+        if idx >= self.fs_headers.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid filesystem index"));
+        }
+
+        let fs_header = self.fs_headers[idx];
+        let section_reader = self.open_fs_region_reader(idx, 0, self.get_fs_size(idx))?;
+        match fs_header.get_hash_info() {
+            Some(SectionHashInfo::HierarchicalSha256(hash_info)) => verify_with_sha256(section_reader, &hash_info),
+            Some(SectionHashInfo::HierarchicalIntegrity(hash_info)) => verify_with_ivfc(section_reader, &hash_info),
+            None => Err(Error::new(ErrorKind::Unsupported, "Section has no known hash type"))
+        }
+    }
+
+    // Every section is checked even after failures, so that the report is complete
+    // The header signature is only checked if the public modulus (see verify_header_signature) is given
+    pub fn verify_all(&mut self, header_modulus: Option<&[u8]>) -> Result<VerifyReport> {
+        let header_signature_valid = match header_modulus {
+            Some(header_modulus) => Some(self.verify_header_signature(header_modulus)?),
+            None => None
+        };
+
+        let mut sections: Vec<SectionVerifyResult> = Vec::with_capacity(self.fs_headers.len());
+        for idx in 0..self.fs_headers.len() {
+            let hash_tree_valid = match self.verify_section(idx) {
+                Ok(hash_tree_valid) => hash_tree_valid,
+                // Corrupted hash info might point outside the section, and sections that can't be verified (unsupported hash/encryption types) can't be considered valid either
+                Err(e) if matches!(e.kind(), ErrorKind::InvalidData | ErrorKind::UnexpectedEof | ErrorKind::InvalidInput | ErrorKind::Unsupported) => false,
+                Err(e) => return Err(e)
+            };
+
+            sections.push(SectionVerifyResult {
+                idx,
+                fs_header_valid: self.is_fs_header_valid(idx),
+                hash_tree_valid
+            });
+        }
+
+        Ok(VerifyReport {
+            header_signature_valid,
+            sections
+        })
+    }
+
+    // Header signature 1 is made with one of Nintendo's fixed keys (selected by header_1_signature_key_generation), whose modulus must be supplied
+    pub fn verify_header_signature(&self, public_modulus: &[u8]) -> Result<bool> {
+        if public_modulus.len() != std::mem::size_of::<RSASignature>() {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid header signature public modulus size: {:#X}", public_modulus.len())));
+        }
+
+        let public_key = match RsaPublicKey::new(BigUint::from_bytes_be(public_modulus), BigUint::from(HEADER_SIGNATURE_PUBLIC_EXPONENT)) {
+            Ok(public_key) => public_key,
+            Err(e) => return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid header signature public key: {}", e)))
+        };
+
+        let signed_region_hash = Sha256::digest(self.header.get_signed_region());
+        Ok(public_key.verify(Pss::new::<Sha256>(), &signed_region_hash, self.header.header_rsa_sig_1.as_slice()).is_ok())
+    }
+
+    // Only the rights ID matters here, the distribution type (system/gamecard) never affects parsing or decryption
     #[inline]
     pub fn needs_title_key_crypto(&self) -> bool {
         self.header.rights_id != [0; 0x10]
     }
 
+    // Offset (relative to the section start) and size of the actual filesystem data
+    fn get_fs_data_region(&self, idx: usize) -> Result<(u64, u64)> {
+        let fs_header = &self.fs_headers[idx];
+
+        // Auto just means the filesystem's usual hash type, an explicit one must match the filesystem (otherwise the hash info would be read as the wrong union variant)
+        let hash_type = match (fs_header.hash_type, fs_header.fs_type) {
+            (HashType::Auto, FileSystemType::PartitionFs) => HashType::HierarchicalSha256,
+            (HashType::Auto, FileSystemType::RomFs) => HashType::HierarchicalIntegrity,
+            (hash_type, _) => hash_type
+        };
+
+        match (fs_header.fs_type, fs_header.read_hash_info(hash_type)) {
+            (FileSystemType::PartitionFs, Some(SectionHashInfo::HierarchicalSha256(hash_info))) => Ok((hash_info.data_offset, hash_info.data_size as u64)),
+            (FileSystemType::RomFs, Some(SectionHashInfo::HierarchicalIntegrity(hash_info))) => {
+                let romfs_level = hash_info.levels.last().copied().unwrap();
+                Ok((romfs_level.offset, romfs_level.size as u64))
+            },
+            (fs_type, _) => Err(Error::new(ErrorKind::InvalidData, format!("Filesystem type {:?} is inconsistent with hash type {:?} (section {})", fs_type, fs_header.hash_type, idx)))
+        }
+    }
+
+    // Region offset is relative to the section start
+    fn open_fs_region_reader(&mut self, idx: usize, region_offset: u64, region_size: u64) -> Result<Shared<dyn ReadSeek>> {
+        let fs_header = self.fs_headers[idx];
+        let fs_start_offset = self.get_fs_offset(idx)?;
+
+        // NCA0 RomFs sections are AES-XTS encrypted (with the key area's XTS key) instead
+        if (self.header.get_version() == Some(Version::NCA0)) && (fs_header.fs_type == FileSystemType::RomFs) {
+            // The key area is left empty for title key crypto, and there's no known XTS key derivation from title keys
+            if self.needs_title_key_crypto() {
+                return Err(Error::new(ErrorKind::Unsupported, format!("NCA0 RomFs sections with title key crypto are not supported (section {})", idx)));
+            }
+
+            self.decrypt_keys()?;
+            let dec_key = self.dec_key_area.unwrap().aes_xts_key.to_vec();
+            let section_reader = new_shared(Aes128XtsReader::new(self.reader.clone(), fs_start_offset, self.get_fs_size(idx), dec_key));
+            return Ok(new_shared(SubReader::new(section_reader, region_offset, region_size)));
+        }
+
+        let region_abs_offset = fs_start_offset + region_offset;
+        match fs_header.encryption_type {
+            EncryptionType::None => Ok(new_shared(PlainReader::new(self.reader.clone(), region_abs_offset, region_size))),
+            EncryptionType::AesCtr => {
+                let dec_key = self.get_aes_ctr_decrypt_key()?;
+                Ok(new_shared(Aes128CtrReader::new(self.reader.clone(), region_abs_offset, region_size, fs_header.ctr, dec_key)))
+            },
+            // Only the patch data itself (without the base data) is readable this way
+            EncryptionType::AesCtrEx => {
+                let subsection_table = self.read_bucket_tree_table(idx, fs_header.patch_info.info_2)?;
+                let (subsections, _) = parse_subsection_table(&subsection_table, fs_header.patch_info.info_2.entry_count as usize)?;
+                let dec_key = self.get_aes_ctr_decrypt_key()?;
+                let section_reader = new_shared(Aes128CtrExReader::new(self.reader.clone(), fs_start_offset, self.get_fs_size(idx), fs_header.ctr, subsections, dec_key));
+                Ok(new_shared(SubReader::new(section_reader, region_offset, region_size)))
+            },
+            enc_type => Err(Error::new(ErrorKind::Unsupported, format!("Unsupported encryption type {:?} (section {})", enc_type, idx)))
+        }
+    }
+
+    // Bucket tree tables are encrypted with the section's regular counter
+    fn read_bucket_tree_table(&mut self, idx: usize, table_info: BucketRelocationInfo) -> Result<Vec<u8>> {
+        if table_info.magic != BUCKET_TREE_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid bucket tree magic"));
+        }
+        if table_info.offset.checked_add(table_info.size as u64).is_none_or(|table_end_offset| table_end_offset > self.get_fs_size(idx)) {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Bucket tree table (offset {:#X}, size {:#X}) exceeds its section", table_info.offset, table_info.size)));
+        }
+
+        let dec_key = self.get_aes_ctr_decrypt_key()?;
+        let mut table_reader = Aes128CtrReader::new(self.reader.clone(), self.get_fs_offset(idx)? + table_info.offset, table_info.size as u64, self.fs_headers[idx].ctr, dec_key);
+        let mut table = vec![0u8; table_info.size];
+        table_reader.read_exact(&mut table)?;
+        Ok(table)
+    }
+
+    // The whole decrypted section (hash data included), with offsets relative to the section start
+    pub fn open_section_reader(&mut self, idx: usize) -> Result<Shared<dyn ReadSeek>> {
+        if idx >= self.fs_headers.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid filesystem index"));
+        }
+
+        self.open_fs_region_reader(idx, 0, self.get_fs_size(idx))
+    }
+
+    // Writes the (re-encrypted) header region with this NCA's current header and FS headers, so that the sections can be written right after it
+    pub fn write_header<W: Write + ?Sized>(&mut self, out: &mut W) -> Result<()> {
+        let mut fs_headers: [Option<FileSystemHeader>; MAX_FILESYSTEM_COUNT] = [None; MAX_FILESYSTEM_COUNT];
+        for (fs_header, slot) in self.fs_headers.iter().zip(self.fs_header_slots.iter()) {
+            fs_headers[*slot] = Some(*fs_header);
+        }
+
+        let key_area = match self.needs_title_key_crypto() {
+            true => None,
+            false => {
+                self.decrypt_keys()?;
+                self.dec_key_area
+            }
+        };
+        write_header(out, &self.header, &fs_headers, key_area.as_ref(), &self.keyset)
+    }
+
+    fn copy_exact<R: Read + ?Sized, W: Write + ?Sized>(reader: &mut R, size: u64, out: &mut W) -> Result<()> {
+        let copied_size = copy_ranged(reader, out, size, DEFAULT_COPY_CHUNK_SIZE, None)?;
+        if copied_size != size {
+            return Err(Error::new(ErrorKind::UnexpectedEof, format!("Expected {:#X} bytes, only {:#X} could be read", size, copied_size)));
+        }
+
+        Ok(())
+    }
+
+    // Like hactool's plaintext output: plain header/FS headers (unused FS headers are written zeroed), decrypted sections and everything else copied as-is
+    pub fn decrypt_to<W: Write + ?Sized>(&mut self, out: &mut W) -> Result<u64> {
+        out.write_all(self.header.as_slice())?;
+        for slot in 0..MAX_FILESYSTEM_COUNT {
+            match self.fs_header_slots.iter().position(|fs_header_slot| *fs_header_slot == slot) {
+                Some(idx) => out.write_all(self.fs_headers[idx].as_slice())?,
+                None => out.write_all(&[0u8; std::mem::size_of::<FileSystemHeader>()])?
+            };
+        }
+
+        let mut sections: Vec<(u64, usize)> = Vec::with_capacity(self.fs_headers.len());
+        for idx in 0..self.fs_headers.len() {
+            sections.push((self.get_fs_offset(idx)?, idx));
+        }
+        sections.sort();
+
+        let mut cur_offset = (std::mem::size_of::<Header>() + std::mem::size_of::<FileSystemHeader>() * MAX_FILESYSTEM_COUNT) as u64;
+        for (fs_offset, idx) in sections {
+            if fs_offset < cur_offset {
+                return Err(Error::new(ErrorKind::InvalidData, format!("Section {} (offset {:#X}) overlaps the previous data (up to {:#X})", idx, fs_offset, cur_offset)));
+            }
+
+            Self::copy_exact(&mut SubReader::new(self.reader.clone(), cur_offset, fs_offset - cur_offset), fs_offset - cur_offset, out)?;
+            let fs_size = self.get_fs_size(idx);
+            Self::copy_exact(&mut SubReader::new(self.open_section_reader(idx)?, 0, fs_size), fs_size, out)?;
+            cur_offset = fs_offset + fs_size;
+        }
+
+        let cnt_size = self.content_size();
+        if cnt_size > cur_offset {
+            Self::copy_exact(&mut SubReader::new(self.reader.clone(), cur_offset, cnt_size - cur_offset), cnt_size - cur_offset, out)?;
+            cur_offset = cnt_size;
+        }
+
+        Ok(cur_offset)
+    }
+
+    #[inline]
+    fn open_fs_data_reader(&mut self, idx: usize) -> Result<Shared<dyn ReadSeek>> {
+        let (data_offset, data_size) = self.get_fs_data_region(idx)?;
+        self.open_fs_region_reader(idx, data_offset, data_size)
+    }
+
     pub fn open_pfs0_filesystem(&mut self, idx: usize) -> Result<PFS0> {
         if idx >= self.fs_headers.len() {
             return Err(Error::new(ErrorKind::InvalidInput, "Invalid filesystem index"));
         }
         
-        let fs_header = &self.fs_headers[idx];
+        let fs_header = self.fs_headers[idx];
         if fs_header.fs_type != FileSystemType::PartitionFs {
             return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid filesystem type (actual type: {:?})", fs_header.fs_type)));
         }
 
-        let fs_start_offset = self.get_fs_offset(idx);
+        self.check_fs_header(idx)?;
+        let pfs0_reader = self.open_fs_data_reader(idx)?;
+        PFS0::new(pfs0_reader)
+    }
 
-        match fs_header.encryption_type {
-            EncryptionType::AesCtr => {
-                let pfs0_abs_offset = fs_start_offset + unsafe { fs_header.hash_info.hierarchical_sha256.pfs0_offset };
-                let dec_key = self.get_aes_ctr_decrypt_key();
-                let pfs0_reader = new_shared(Aes128CtrReader::new(self.reader.clone(), pfs0_abs_offset, fs_header.ctr, dec_key));
+    // Every block read is checked against the section's hash table, which is checked against the master hash here
+    pub fn open_pfs0_filesystem_verified(&mut self, idx: usize) -> Result<PFS0> {
+        if idx >= self.fs_headers.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid filesystem index"));
+        }
 
-                PFS0::new(pfs0_reader)
-            },
-            enc_type => todo!("Unsupported crypto type: {:?}", enc_type)
+        let fs_header = self.fs_headers[idx];
+        if (fs_header.fs_type != FileSystemType::PartitionFs) || (fs_header.hash_type != HashType::HierarchicalSha256) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid filesystem/hash type (actual types: {:?}, {:?})", fs_header.fs_type, fs_header.hash_type)));
         }
+
+        self.check_fs_header(idx)?;
+        let section_reader = self.open_fs_region_reader(idx, 0, self.get_fs_size(idx))?;
+        let hash_info = match fs_header.get_hash_info() {
+            Some(SectionHashInfo::HierarchicalSha256(hash_info)) => hash_info,
+            _ => unreachable!()
+        };
+        let verified_reader = Sha256VerifiedReader::new(section_reader, hash_info)?;
+        PFS0::new(new_shared(verified_reader))
     }
 
     pub fn open_romfs_filesystem(&mut self, idx: usize) -> Result<RomFs> {
@@ -446,22 +1120,312 @@ impl NCA {
             return Err(Error::new(ErrorKind::InvalidInput, "Invalid filesystem index"));
         }
         
-        let fs_header = &self.fs_headers[idx];
+        let fs_header = self.fs_headers[idx];
         if fs_header.fs_type != FileSystemType::RomFs {
             return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid filesystem type (actual type: {:?})", fs_header.fs_type)));
         }
 
-        let fs_start_offset = self.get_fs_offset(idx);
+        if fs_header.encryption_type == EncryptionType::AesCtrEx {
+            return Err(Error::new(ErrorKind::InvalidInput, "Patch RomFs sections can only be opened along with their base NCA (see open_patched_romfs_filesystem)"));
+        }
 
-        match fs_header.encryption_type {
-            EncryptionType::AesCtr => {
-                let romfs_offset = fs_start_offset + unsafe { fs_header.hash_info.hierarchical_integrity.levels.last().as_ref().unwrap().offset };
-                let dec_key = self.get_aes_ctr_decrypt_key();
-                let romfs_reader = new_shared(Aes128CtrReader::new(self.reader.clone(), romfs_offset, fs_header.ctr, dec_key));
+        self.check_fs_header(idx)?;
+        let romfs_reader = self.open_fs_data_reader(idx)?;
+        RomFs::new(romfs_reader)
+    }
+
+    // Every block read is checked up the IVFC tree, whose first level is checked against the master hash here
+    pub fn open_romfs_filesystem_verified(&mut self, idx: usize) -> Result<RomFs> {
+        if idx >= self.fs_headers.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid filesystem index"));
+        }
+
+        let fs_header = self.fs_headers[idx];
+        if (fs_header.fs_type != FileSystemType::RomFs) || (fs_header.hash_type != HashType::HierarchicalIntegrity) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid filesystem/hash type (actual types: {:?}, {:?})", fs_header.fs_type, fs_header.hash_type)));
+        }
+
+        if fs_header.encryption_type == EncryptionType::AesCtrEx {
+            return Err(Error::new(ErrorKind::InvalidInput, "Patch RomFs sections can only be opened along with their base NCA (see open_patched_romfs_filesystem)"));
+        }
 
-                RomFs::new(romfs_reader)
+        self.check_fs_header(idx)?;
+        let section_reader = self.open_fs_region_reader(idx, 0, self.get_fs_size(idx))?;
+        let hash_info = match fs_header.get_hash_info() {
+            Some(SectionHashInfo::HierarchicalIntegrity(hash_info)) => hash_info,
+            _ => unreachable!()
+        };
+        let verified_reader = IvfcVerifiedReader::new(section_reader, hash_info)?;
+        RomFs::new(new_shared(verified_reader))
+    }
+
+    // The base NCA's RomFs section is the one in the same FS entry slot
+    pub fn open_patched_romfs_filesystem(&mut self, idx: usize, base: &mut NCA) -> Result<RomFs> {
+        if idx >= self.fs_headers.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid filesystem index"));
+        }
+
+        let fs_header = self.fs_headers[idx];
+        if (fs_header.fs_type != FileSystemType::RomFs) || (fs_header.encryption_type != EncryptionType::AesCtrEx) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Not a patch RomFs section (type: {:?}, encryption type: {:?})", fs_header.fs_type, fs_header.encryption_type)));
+        }
+
+        self.check_fs_header(idx)?;
+        let slot = self.fs_header_slots[idx];
+        let base_idx = match base.fs_header_slots.iter().position(|base_slot| *base_slot == slot) {
+            Some(base_idx) if base.fs_headers[base_idx].fs_type == FileSystemType::RomFs => base_idx,
+            _ => return Err(Error::new(ErrorKind::NotFound, "Base NCA has no matching RomFs section"))
+        };
+        base.check_fs_header(base_idx)?;
+
+        let base_reader = base.open_fs_region_reader(base_idx, 0, base.get_fs_size(base_idx))?;
+        let patch_reader = self.open_fs_region_reader(idx, 0, self.get_fs_size(idx))?;
+
+        let relocation_table = self.read_bucket_tree_table(idx, fs_header.patch_info.info)?;
+        let (relocation_entries, patched_size) = parse_relocation_table(&relocation_table, fs_header.patch_info.info.entry_count as usize)?;
+        let patched_reader = new_shared(IndirectReader::new(vec![base_reader, patch_reader], relocation_entries, patched_size));
+
+        // Hash info (and thus the RomFs location) refers to the patched section
+        let (data_offset, data_size) = self.get_fs_data_region(idx)?;
+        RomFs::new(new_shared(SubReader::new(patched_reader, data_offset, data_size)))
+    }
+
+    // Quick check (before doing anything expensive) of whether the section decrypts to something sensible with the current keys
+    pub fn self_test_section(&mut self, idx: usize) -> Result<bool> {
+        if idx >= self.fs_headers.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid filesystem index"));
+        }
+
+        let fs_reader = self.open_fs_data_reader(idx)?;
+        let mut fs_start = [0u8; 0x8];
+        if fs_reader.lock().unwrap().read_exact(&mut fs_start).is_err() {
+            return Ok(false);
+        }
+
+        match self.fs_headers[idx].fs_type {
+            FileSystemType::PartitionFs => Ok(u32::from_le_bytes(fs_start[..0x4].try_into().unwrap()) == crate::pfs0::Header::MAGIC),
+            // RomFs headers have no magic, but always start with their own size
+            FileSystemType::RomFs => Ok(u64::from_le_bytes(fs_start) == std::mem::size_of::<crate::romfs::Header>() as u64)
+        }
+    }
+
+    pub fn open_filesystem(&mut self, idx: usize) -> Result<FileSystem> {
+        if idx >= self.fs_headers.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid filesystem index"));
+        }
+
+        match self.fs_headers[idx].fs_type {
+            FileSystemType::PartitionFs => Ok(FileSystem::PartitionFs(self.open_pfs0_filesystem(idx)?)),
+            FileSystemType::RomFs => Ok(FileSystem::RomFs(self.open_romfs_filesystem(idx)?))
+        }
+    }
+
+    #[inline]
+    pub fn get_section_fs_type(&self, idx: usize) -> Option<FileSystemType> {
+        self.fs_headers.get(idx).map(|fs_header| fs_header.fs_type)
+    }
+
+    #[inline]
+    pub fn get_section_hash_type(&self, idx: usize) -> Option<HashType> {
+        self.fs_headers.get(idx).map(|fs_header| fs_header.hash_type)
+    }
+
+    #[inline]
+    pub fn get_section_encryption_type(&self, idx: usize) -> Option<EncryptionType> {
+        self.fs_headers.get(idx).map(|fs_header| fs_header.encryption_type)
+    }
+
+    // None for invalid indices as well as sections without a known hash type
+    #[inline]
+    pub fn get_section_hash_info(&self, idx: usize) -> Option<SectionHashInfo> {
+        self.fs_headers.get(idx)?.get_hash_info()
+    }
+
+    // Absolute offset and size of the section within the NCA (as stored, thus including hash data), sparse sections have no offset
+    #[inline]
+    pub fn get_section_offset(&self, idx: usize) -> Option<u64> {
+        (idx < self.fs_headers.len()).then(|| self.get_fs_offset(idx).ok())?
+    }
+
+    #[inline]
+    pub fn get_section_size(&self, idx: usize) -> Option<u64> {
+        (idx < self.fs_headers.len()).then(|| self.get_fs_size(idx))
+    }
+
+    // Roles are given by the FS entry slot: program NCAs hold the ExeFs, RomFs and logo (in that order), meta NCAs the CNMT PartitionFs and the rest only a RomFs
+    pub fn section_role(&self, idx: usize) -> Option<SectionRole> {
+        let fs_type = self.fs_headers.get(idx)?.fs_type;
+        let slot = self.fs_header_slots[idx];
+
+        match (self.header.cnt_type, slot, fs_type) {
+            (ContentType::Program, 0, FileSystemType::PartitionFs) => Some(SectionRole::ExeFs),
+            (ContentType::Program, 1, FileSystemType::RomFs) => Some(SectionRole::RomFs),
+            (ContentType::Program, 2, FileSystemType::PartitionFs) => Some(SectionRole::Logo),
+            (ContentType::Meta, 0, FileSystemType::PartitionFs) => Some(SectionRole::Meta),
+            (ContentType::Control | ContentType::Manual | ContentType::Data | ContentType::PublicData, 0, FileSystemType::RomFs) => Some(SectionRole::RomFs),
+            _ => None
+        }
+    }
+
+    pub fn open_role(&mut self, role: SectionRole) -> Result<FileSystem> {
+        match (0..self.fs_headers.len()).find(|idx| self.section_role(*idx) == Some(role)) {
+            Some(idx) => self.open_filesystem(idx),
+            None => Err(Error::new(ErrorKind::NotFound, format!("NCA has no {:?} section", role)))
+        }
+    }
+
+    pub fn open_exefs(&mut self) -> Result<PFS0> {
+        if self.header.cnt_type != ContentType::Program {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("NCA is not a program NCA (actual type: {:?})", self.header.cnt_type)));
+        }
+
+        match self.open_role(SectionRole::ExeFs)? {
+            FileSystem::PartitionFs(exefs) => Ok(exefs),
+            FileSystem::RomFs(_) => Err(Error::new(ErrorKind::InvalidData, "ExeFs section is not a PartitionFs"))
+        }
+    }
+
+    pub fn read_npdm(&mut self) -> Result<Npdm> {
+        let mut exefs = self.open_exefs()?;
+
+        let npdm_idx = match exefs.find_file("main.npdm") {
+            Some(idx) => idx,
+            None => return Err(Error::new(ErrorKind::NotFound, "ExeFs has no main.npdm"))
+        };
+
+        let npdm_size = exefs.get_file_size(npdm_idx)?;
+        let mut npdm_data = vec![0u8; npdm_size];
+        exefs.read_file(npdm_idx, 0, &mut npdm_data)?;
+
+        Npdm::new(new_shared(DataReader::new(npdm_data)))
+    }
+
+    pub fn open_meta(&mut self) -> Result<PFS0> {
+        if self.header.cnt_type != ContentType::Meta {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("NCA is not a meta NCA (actual type: {:?})", self.header.cnt_type)));
+        }
+
+        match self.open_role(SectionRole::Meta)? {
+            FileSystem::PartitionFs(meta) => Ok(meta),
+            FileSystem::RomFs(_) => Err(Error::new(ErrorKind::InvalidData, "Meta section is not a PartitionFs"))
+        }
+    }
+
+    pub fn read_cnmt(&mut self) -> Result<Cnmt> {
+        let mut meta = self.open_meta()?;
+
+        let cnmt_idx = match meta.list_files()?.iter().position(|file_name| file_name.ends_with(".cnmt")) {
+            Some(idx) => idx,
+            None => return Err(Error::new(ErrorKind::NotFound, "Meta NCA has no CNMT file"))
+        };
+
+        let cnmt_size = meta.get_file_size(cnmt_idx)?;
+        let mut cnmt_data = vec![0u8; cnmt_size];
+        meta.read_file(cnmt_idx, 0, &mut cnmt_data)?;
+
+        Cnmt::new(new_shared(DataReader::new(cnmt_data)))
+    }
+
+    pub fn read_nacp(&mut self) -> Result<Nacp> {
+        if self.header.cnt_type != ContentType::Control {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("NCA is not a control NCA (actual type: {:?})", self.header.cnt_type)));
+        }
+
+        let mut control_romfs = match self.open_role(SectionRole::RomFs)? {
+            FileSystem::RomFs(romfs) => romfs,
+            FileSystem::PartitionFs(_) => return Err(Error::new(ErrorKind::InvalidData, "Control section is not a RomFs"))
+        };
+        let nacp_data = control_romfs.read_range(String::from("control.nacp"), 0, crate::nacp::NACP_SIZE)?;
+        Nacp::new(new_shared(DataReader::new(nacp_data)))
+    }
+
+    pub fn read_control_info(&mut self, language: Language) -> Result<NcaControlInfo> {
+        let nacp = self.read_nacp()?;
+
+        let title = match nacp.get_title(language) {
+            Some(title) => title.clone(),
+            None => return Err(Error::new(ErrorKind::NotFound, "NACP has no application titles"))
+        };
+
+        Ok(NcaControlInfo {
+            name: title.name,
+            author: title.publisher,
+            display_version: nacp.display_version,
+            application_id: self.header.program_id
+        })
+    }
+}
+
+// The inverse of NCA::new_header_only: FS header hashes are recomputed, and the key area is encrypted unless none is given (like for title key crypto NCAs, whose header key area is kept as-is)
+pub fn write_header<W: Write + ?Sized>(out: &mut W, header: &Header, fs_headers: &[Option<FileSystemHeader>; MAX_FILESYSTEM_COUNT], key_area: Option<&KeyArea>, keyset: &Keyset) -> Result<()> {
+    if keyset.header_key == [0; 0x20] {
+        return Err(Error::new(ErrorKind::InvalidInput, "Header key (header_key) not present in keyset"));
+    }
+
+    let version = match header.get_version() {
+        Some(version) => version,
+        None => return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid NCA magic {:?} (only NCA0, NCA2 and NCA3 are supported)", String::from_utf8_lossy(&header.magic.to_le_bytes()))))
+    };
+
+    let mut header = *header;
+    if let Some(key_area) = key_area {
+        header.encrypted_key_area = KeyArea::from_slice(&encrypt_key_area(key_area, keyset, header.key_area_encryption_key_index, header.get_key_generation())?);
+    }
+
+    let mut fs_headers_buf = vec![0u8; std::mem::size_of::<FileSystemHeader>() * MAX_FILESYSTEM_COUNT];
+    for (slot, (fs_header, fs_header_buf)) in fs_headers.iter().zip(fs_headers_buf.chunks_exact_mut(std::mem::size_of::<FileSystemHeader>())).enumerate() {
+        match fs_header {
+            Some(fs_header) => {
+                fs_header_buf.copy_from_slice(fs_header.as_slice());
+                header.fs_header_hashes[slot].hash = Sha256::digest(fs_header_buf).into();
             },
-            enc_type => todo!("Unsupported crypto type: {:?}", enc_type)
+            None => header.fs_header_hashes[slot].hash = [0; 0x20]
+        };
+    }
+
+    let cipher_1 = Aes128::new_varkey(&keyset.header_key[..0x10]).unwrap();
+    let cipher_2 = Aes128::new_varkey(&keyset.header_key[0x10..]).unwrap();
+    let xts = Xts128::new(cipher_1, cipher_2);
+
+    let mut header_buf = header.as_slice().to_vec();
+    xts.encrypt_area(&mut header_buf, SECTOR_SIZE, 0, get_nintendo_tweak);
+    match version {
+        Version::NCA3 => xts.encrypt_area(&mut fs_headers_buf, SECTOR_SIZE, 2, get_nintendo_tweak),
+        Version::NCA2 | Version::NCA0 => {
+            for fs_header_buf in fs_headers_buf.chunks_exact_mut(std::mem::size_of::<FileSystemHeader>()) {
+                xts.encrypt_area(fs_header_buf, SECTOR_SIZE, 0, get_nintendo_tweak);
+            }
         }
+    };
+
+    out.write_all(&header_buf)?;
+    out.write_all(&fs_headers_buf)
+}
+
+// Only headers are read (so title keys aren't needed), results are sorted by path
+// Split contents (directories named like NCAs, as found on SD cards) and files which aren't valid NCAs are skipped
+pub fn scan_dir(dir: &Path, keyset: &Keyset) -> Result<Vec<(PathBuf, Header)>> {
+    // Otherwise every NCA would just be skipped
+    if keyset.header_key == [0; 0x20] {
+        return Err(Error::new(ErrorKind::InvalidInput, "Header key (header_key) not present in keyset"));
     }
-}
\ No newline at end of file
+
+    let mut ncas: Vec<(PathBuf, Header)> = Vec::new();
+    for dir_entry in read_dir(dir)? {
+        let dir_entry = dir_entry?;
+        let path = dir_entry.path();
+        let is_nca_file = dir_entry.file_type()?.is_file() && path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("nca"));
+        if !is_nca_file {
+            continue;
+        }
+
+        match NCA::new_header_only(new_shared(File::open(&path)?), keyset) {
+            Ok(nca) => ncas.push((path, nca.header)),
+            Err(e) if matches!(e.kind(), ErrorKind::InvalidInput | ErrorKind::InvalidData | ErrorKind::UnexpectedEof) => continue,
+            Err(e) => return Err(e)
+        };
+    }
+
+    ncas.sort_by(|(path_a, _), (path_b, _)| path_a.cmp(path_b));
+    Ok(ncas)
+}
@@ -0,0 +1,141 @@
+use std::io::{Error, ErrorKind, Result};
+use crate::util::{PlainData, ReadSeek, Shared, reader_read_at, reader_read_val_at};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+#[repr(C)]
+pub struct Header {
+    pub magic: u32,
+    pub signature_key_generation: u32,
+    pub reserved: [u8; 0x4],
+    pub flags: u8,
+    pub reserved_1: u8,
+    pub main_thread_priority: u8,
+    pub main_thread_core_number: u8,
+    pub reserved_2: [u8; 0x4],
+    pub system_resource_size: u32,
+    pub version: u32,
+    pub main_thread_stack_size: u32,
+    pub name: [u8; 0x10],
+    pub product_code: [u8; 0x10],
+    pub reserved_3: [u8; 0x20],
+    pub reserved_4: [u8; 0x10],
+    pub aci_offset: u32,
+    pub aci_size: u32,
+    pub acid_offset: u32,
+    pub acid_size: u32
+}
+
+unsafe impl PlainData for Header {}
+
+impl Header {
+    pub const MAGIC: u32 = u32::from_le_bytes(*b"META");
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+#[repr(C)]
+pub struct AciHeader {
+    pub magic: u32,
+    pub reserved: [u8; 0xC],
+    pub program_id: u64,
+    pub reserved_1: [u8; 0x8],
+    pub fs_access_control_offset: u32,
+    pub fs_access_control_size: u32,
+    pub service_access_control_offset: u32,
+    pub service_access_control_size: u32,
+    pub kernel_capability_offset: u32,
+    pub kernel_capability_size: u32,
+    pub reserved_2: [u8; 0x8]
+}
+
+unsafe impl PlainData for AciHeader {}
+
+impl AciHeader {
+    pub const MAGIC: u32 = u32::from_le_bytes(*b"ACI0");
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ServiceAccess {
+    pub name: String,
+    pub is_server: bool
+}
+
+pub struct Npdm {
+    pub header: Header,
+    pub aci_header: AciHeader,
+    pub fs_access_control: Vec<u8>,
+    pub service_access_control: Vec<ServiceAccess>,
+    pub kernel_capabilities: Vec<u32>
+}
+
+fn read_region(reader: &Shared<dyn ReadSeek>, offset: u64, size: u32) -> Result<Vec<u8>> {
+    let mut data = vec![0u8; size as usize];
+    reader_read_at(reader, offset, &mut data)?;
+    Ok(data)
+}
+
+fn parse_service_access_control(data: &[u8]) -> Result<Vec<ServiceAccess>> {
+    let mut services: Vec<ServiceAccess> = Vec::new();
+
+    let mut offset: usize = 0;
+    while offset < data.len() {
+        let control = data[offset];
+        if control == 0 {
+            break;
+        }
+
+        let name_len = (control & 0x7) as usize + 1;
+        let name_start = offset + 1;
+        let name_end = name_start + name_len;
+        if name_end > data.len() {
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid NPDM service access control entry"));
+        }
+
+        services.push(ServiceAccess {
+            name: String::from_utf8_lossy(&data[name_start..name_end]).into_owned(),
+            is_server: (control & 0x80) != 0
+        });
+        offset = name_end;
+    }
+
+    Ok(services)
+}
+
+impl Npdm {
+    pub fn new(reader: Shared<dyn ReadSeek>) -> Result<Self> {
+        let header: Header = reader_read_val_at(&reader, 0)?;
+        if header.magic != Header::MAGIC {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid NPDM magic"));
+        }
+
+        let aci_header: AciHeader = reader_read_val_at(&reader, header.aci_offset as u64)?;
+        if aci_header.magic != AciHeader::MAGIC {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid NPDM ACI0 magic"));
+        }
+
+        let aci_offset = header.aci_offset as u64;
+        let fs_access_control = read_region(&reader, aci_offset + aci_header.fs_access_control_offset as u64, aci_header.fs_access_control_size)?;
+        let service_access_control_data = read_region(&reader, aci_offset + aci_header.service_access_control_offset as u64, aci_header.service_access_control_size)?;
+        let kernel_capability_data = read_region(&reader, aci_offset + aci_header.kernel_capability_offset as u64, aci_header.kernel_capability_size)?;
+
+        let service_access_control = parse_service_access_control(&service_access_control_data)?;
+        let kernel_capabilities = kernel_capability_data.chunks_exact(std::mem::size_of::<u32>()).map(|c| u32::from_le_bytes(c.try_into().unwrap())).collect();
+
+        Ok(Self {
+            header,
+            aci_header,
+            fs_access_control,
+            service_access_control,
+            kernel_capabilities
+        })
+    }
+
+    #[inline]
+    pub fn get_program_id(&self) -> u64 {
+        self.aci_header.program_id
+    }
+
+    pub fn get_name(&self) -> String {
+        let name_len = self.header.name.iter().position(|c| *c == 0).unwrap_or(self.header.name.len());
+        String::from_utf8_lossy(&self.header.name[..name_len]).into_owned()
+    }
+}
\ No newline at end of file
@@ -0,0 +1,113 @@
+use std::io::{Error, ErrorKind, Result};
+use crate::util::{PlainData, ReadSeek, Shared, reader_read_val_at};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+#[repr(C)]
+pub struct SegmentHeader {
+    pub file_offset: u32,
+    pub memory_offset: u32,
+    pub size: u32
+}
+
+unsafe impl PlainData for SegmentHeader {}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+#[repr(C)]
+pub struct SectionInfo {
+    pub offset: u32,
+    pub size: u32
+}
+
+unsafe impl PlainData for SectionInfo {}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum Segment {
+    Text,
+    Rodata,
+    Data
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+#[repr(C)]
+pub struct Header {
+    pub magic: u32,
+    pub version: u32,
+    pub reserved: [u8; 0x4],
+    pub flags: u32,
+    pub text_segment: SegmentHeader,
+    pub module_name_offset: u32,
+    pub rodata_segment: SegmentHeader,
+    pub module_name_size: u32,
+    pub data_segment: SegmentHeader,
+    pub bss_size: u32,
+    pub module_id: [u8; 0x20],
+    pub text_file_size: u32,
+    pub rodata_file_size: u32,
+    pub data_file_size: u32,
+    pub reserved_1: [u8; 0x1C],
+    pub api_info: SectionInfo,
+    pub dynstr: SectionInfo,
+    pub dynsym: SectionInfo,
+    pub text_hash: [u8; 0x20],
+    pub rodata_hash: [u8; 0x20],
+    pub data_hash: [u8; 0x20]
+}
+
+unsafe impl PlainData for Header {}
+
+impl Header {
+    pub const MAGIC: u32 = u32::from_le_bytes(*b"NSO0");
+
+    #[inline]
+    pub fn get_segment(&self, segment: Segment) -> SegmentHeader {
+        match segment {
+            Segment::Text => self.text_segment,
+            Segment::Rodata => self.rodata_segment,
+            Segment::Data => self.data_segment
+        }
+    }
+
+    // Size of the segment as stored in the file (LZ4-compressed if the corresponding flag is set)
+    #[inline]
+    pub fn get_segment_file_size(&self, segment: Segment) -> u32 {
+        match segment {
+            Segment::Text => self.text_file_size,
+            Segment::Rodata => self.rodata_file_size,
+            Segment::Data => self.data_file_size
+        }
+    }
+
+    #[inline]
+    pub fn is_segment_compressed(&self, segment: Segment) -> bool {
+        (self.flags & (1 << segment as u32)) != 0
+    }
+
+    #[inline]
+    pub fn is_segment_hash_checked(&self, segment: Segment) -> bool {
+        (self.flags & (1 << (segment as u32 + 3))) != 0
+    }
+
+    // Build IDs are usually displayed without trailing zeros (only the first 0x14 bytes are typically used)
+    pub fn get_build_id_string(&self) -> String {
+        let build_id_len = self.module_id.iter().rposition(|b| *b != 0).map(|pos| pos + 1).unwrap_or(0);
+        hex::encode_upper(&self.module_id[..build_id_len])
+    }
+}
+
+pub struct Nso {
+    pub header: Header
+}
+
+impl Nso {
+    pub fn new(reader: Shared<dyn ReadSeek>) -> Result<Self> {
+        let header: Header = reader_read_val_at(&reader, 0)?;
+        if header.magic != Header::MAGIC {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid NSO magic"));
+        }
+
+        Ok(Self {
+            header
+        })
+    }
+}
@@ -0,0 +1,181 @@
+use std::fs::{File, create_dir_all};
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use crate::cnmt::{ContentInfo, ContentType};
+use crate::key::Keyset;
+use crate::nca::NCA;
+use crate::pfs0::PFS0;
+use crate::ticket::Ticket;
+use crate::util::{DEFAULT_COPY_CHUNK_SIZE, ReadSeek, Shared, copy_ranged, get_member_out_path, hash_reader, new_shared};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CryptoKind {
+    Standard,
+    // Title keys are considered available if registered in the keyset or provided by a common ticket inside the NSP
+    TitleKey { title_keys_available: bool }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ContentVerifyResult {
+    pub file_name: String,
+    pub content_type: ContentType,
+    // None if the content is not present in the NSP
+    pub hash_valid: Option<bool>
+}
+
+impl ContentVerifyResult {
+    // Delta fragments are commonly stripped from NSPs, so they're fine to be missing
+    #[inline]
+    pub fn is_valid(&self) -> bool {
+        self.hash_valid.unwrap_or(self.content_type == ContentType::DeltaFragment)
+    }
+}
+
+pub struct Nsp {
+    pub pfs0: PFS0
+}
+
+impl Nsp {
+    pub fn new(reader: Shared<dyn ReadSeek>) -> Result<Self> {
+        Ok(Self {
+            pfs0: PFS0::new(reader)?
+        })
+    }
+
+    pub fn for_each_nca<F: FnMut(&str, &mut NCA)>(&mut self, keyset: &Keyset, mut f: F) -> Result<()> {
+        let files = self.pfs0.list_files()?;
+
+        for (idx, file_name) in files.iter().enumerate() {
+            // Tickets, certificates and other non-NCA members are skipped
+            if !file_name.ends_with(".nca") {
+                continue;
+            }
+
+            let nca_reader = new_shared(self.pfs0.open_file(idx)?);
+            let mut nca = NCA::new(nca_reader, keyset, None)?;
+            f(file_name, &mut nca);
+        }
+
+        Ok(())
+    }
+
+    // Only NCA headers are inspected, so this works even when the title keys are not available
+    pub fn crypto_kind(&mut self, keyset: &Keyset) -> Result<CryptoKind> {
+        let mut rights_ids: Vec<[u8; 0x10]> = Vec::new();
+        let mut ticket_rights_ids: Vec<[u8; 0x10]> = Vec::new();
+
+        for (idx, file_name) in self.pfs0.list_files()?.iter().enumerate() {
+            if file_name.ends_with(".tik") {
+                // Invalid or personalized tickets just don't provide a usable title key
+                if let Ok(ticket) = Ticket::new(new_shared(self.pfs0.open_file(idx)?)) {
+                    if ticket.get_encrypted_title_key().is_ok() {
+                        ticket_rights_ids.push(ticket.get_rights_id());
+                    }
+                }
+                continue;
+            }
+            if !file_name.ends_with(".nca") {
+                continue;
+            }
+
+            let nca = NCA::new_header_only(new_shared(self.pfs0.open_file(idx)?), keyset)?;
+            if nca.needs_title_key_crypto() && !rights_ids.contains(&nca.header.rights_id) {
+                rights_ids.push(nca.header.rights_id);
+            }
+        }
+
+        if rights_ids.is_empty() {
+            Ok(CryptoKind::Standard)
+        }
+        else {
+            Ok(CryptoKind::TitleKey {
+                title_keys_available: rights_ids.iter().all(|rights_id| keyset.get_title_key(rights_id).is_some() || ticket_rights_ids.contains(rights_id))
+            })
+        }
+    }
+
+    fn extract_file(&mut self, idx: usize, path: &Path) -> Result<u64> {
+        let mut file_reader = self.pfs0.open_file(idx)?;
+        let mut out_file = File::create(path)?;
+        let file_size = file_reader.get_size();
+        copy_ranged(&mut file_reader, &mut out_file, file_size, DEFAULT_COPY_CHUNK_SIZE, None)
+    }
+
+    fn check_content_hash(&mut self, idx: usize, content: &ContentInfo) -> Result<bool> {
+        let mut file_reader = self.pfs0.open_file(idx)?;
+        let file_size = file_reader.get_size();
+        if file_size != content.get_size() {
+            return Ok(false);
+        }
+
+        Ok(hash_reader(&mut file_reader)? == content.hash)
+    }
+
+    // Every meta NCA's CNMT is checked (the meta NCAs themselves aren't listed in their CNMTs)
+    pub fn verify_contents(&mut self, keyset: &Keyset) -> Result<Vec<ContentVerifyResult>> {
+        let files = self.pfs0.list_files()?;
+        let mut results: Vec<ContentVerifyResult> = Vec::new();
+        let mut meta_found = false;
+
+        for (idx, file_name) in files.iter().enumerate() {
+            if !file_name.ends_with(".cnmt.nca") {
+                continue;
+            }
+
+            let mut meta_nca = NCA::new(new_shared(self.pfs0.open_file(idx)?), keyset, None)?;
+            let cnmt = meta_nca.read_cnmt()?;
+            meta_found = true;
+            for content in cnmt.contents.iter() {
+                let content_file_name = content.get_file_name();
+                let hash_valid = match files.iter().position(|file_name| file_name.eq_ignore_ascii_case(&content_file_name)) {
+                    Some(content_idx) => Some(self.check_content_hash(content_idx, content)?),
+                    None => None
+                };
+
+                results.push(ContentVerifyResult {
+                    file_name: content_file_name,
+                    content_type: content.content_type,
+                    hash_valid
+                });
+            }
+        }
+
+        if !meta_found {
+            return Err(Error::new(ErrorKind::NotFound, "NSP has no meta NCA"));
+        }
+        Ok(results)
+    }
+
+    // Member names come from the NSP itself, so they're checked not to escape the output dir
+    pub fn extract_registered(&mut self, out_dir: &Path, keyset: &Keyset) -> Result<()> {
+        create_dir_all(out_dir)?;
+        let files = self.pfs0.list_files()?;
+
+        for (idx, file_name) in files.iter().enumerate() {
+            if file_name.ends_with(".cnmt.nca") {
+                let mut meta_nca = NCA::new(new_shared(self.pfs0.open_file(idx)?), keyset, None)?;
+                let cnmt = meta_nca.read_cnmt()?;
+
+                for content in cnmt.contents.iter() {
+                    let content_file_name = content.get_file_name();
+
+                    match files.iter().position(|file_name| file_name.eq_ignore_ascii_case(&content_file_name)) {
+                        Some(content_idx) => {
+                            self.extract_file(content_idx, &get_member_out_path(out_dir, &content_file_name)?)?;
+                        },
+                        // Delta fragments are commonly stripped from NSPs
+                        None if content.content_type == ContentType::DeltaFragment => continue,
+                        None => return Err(Error::new(ErrorKind::NotFound, format!("Content '{}' referenced by the CNMT is not present", content_file_name)))
+                    };
+                }
+
+                self.extract_file(idx, &get_member_out_path(out_dir, &file_name.to_lowercase())?)?;
+            }
+            else if file_name.ends_with(".tik") || file_name.ends_with(".cert") {
+                self.extract_file(idx, &get_member_out_path(out_dir, file_name)?)?;
+            }
+        }
+
+        Ok(())
+    }
+}
\ No newline at end of file
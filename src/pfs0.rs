@@ -1,5 +1,8 @@
-use std::io::{Error, ErrorKind, Result, SeekFrom};
-use crate::util::{ReadSeek, Shared, reader_read_val};
+use std::fs::{File, create_dir_all};
+use std::io::{Cursor, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::path::Path;
+use sha2::{Digest, Sha256};
+use crate::util::{DEFAULT_COPY_CHUNK_SIZE, DecodeMode, PlainData, ReadSeek, Shared, SubReader, align_up, copy_ranged, decode_name, get_member_out_path, hash_reader, new_shared, reader_read_at, reader_read_val_at, writer_write_val};
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
 #[repr(C)]
@@ -10,6 +13,8 @@ pub struct Header {
     pub reserved: [u8; 0x4]
 }
 
+unsafe impl PlainData for Header {}
+
 impl Header {
     pub const MAGIC: u32 = u32::from_le_bytes(*b"PFS0");
 }
@@ -23,82 +28,382 @@ pub struct FileEntry {
     pub reserved: [u8; 0x4]
 }
 
+unsafe impl PlainData for FileEntry {}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Sha256HashInfo {
+    pub master_hash: [u8; 0x20],
+    pub block_size: usize,
+    pub hash_table_offset: u64,
+    pub hash_table_size: usize,
+    pub data_offset: u64,
+    pub data_size: usize
+}
+
+pub fn verify_with_sha256(reader: Shared<dyn ReadSeek>, hash_info: &Sha256HashInfo) -> Result<bool> {
+    if hash_info.block_size == 0 {
+        return Err(Error::new(ErrorKind::InvalidInput, "Invalid hash block size"));
+    }
+
+    let mut hash_table = vec![0u8; hash_info.hash_table_size];
+    reader_read_at(&reader, hash_info.hash_table_offset, &mut hash_table)?;
+    if Sha256::digest(&hash_table).as_slice() != hash_info.master_hash {
+        return Ok(false);
+    }
+
+    let block_count = hash_info.data_size.div_ceil(hash_info.block_size);
+    if block_count * 0x20 > hash_table.len() {
+        return Ok(false);
+    }
+
+    let mut block = vec![0u8; hash_info.block_size];
+    for (block_idx, block_hash) in hash_table.chunks_exact(0x20).take(block_count).enumerate() {
+        // The last block is only hashed up to the end of the data
+        let block_offset = block_idx * hash_info.block_size;
+        let block_size = std::cmp::min(hash_info.block_size, hash_info.data_size - block_offset);
+
+        reader_read_at(&reader, hash_info.data_offset + block_offset as u64, &mut block[..block_size])?;
+        if Sha256::digest(&block[..block_size]).as_slice() != block_hash {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+// Reads the data region of a HierarchicalSha256 section, verifying every block against the hash table (itself verified when created)
+pub struct Sha256VerifiedReader {
+    reader: Shared<dyn ReadSeek>,
+    hash_info: Sha256HashInfo,
+    hash_table: Vec<u8>,
+    cur_block: Option<(usize, Vec<u8>)>,
+    offset: u64
+}
+
+impl Sha256VerifiedReader {
+    pub fn new(reader: Shared<dyn ReadSeek>, hash_info: Sha256HashInfo) -> Result<Self> {
+        if hash_info.block_size == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid hash block size"));
+        }
+
+        let mut hash_table = vec![0u8; hash_info.hash_table_size];
+        reader_read_at(&reader, hash_info.hash_table_offset, &mut hash_table)?;
+        if Sha256::digest(&hash_table).as_slice() != hash_info.master_hash {
+            return Err(Error::new(ErrorKind::InvalidData, "Hash table does not match the master hash"));
+        }
+        if hash_info.data_size.div_ceil(hash_info.block_size) * 0x20 > hash_table.len() {
+            return Err(Error::new(ErrorKind::InvalidData, "Hash table is too small for the data region"));
+        }
+
+        Ok(Self {
+            reader,
+            hash_info,
+            hash_table,
+            cur_block: None,
+            offset: 0
+        })
+    }
+
+    // The last verified block is kept, since most reads are small and sequential
+    fn load_block(&mut self, block_idx: usize) -> Result<&[u8]> {
+        if self.cur_block.as_ref().is_none_or(|(cur_block_idx, _)| *cur_block_idx != block_idx) {
+            let block_offset = block_idx * self.hash_info.block_size;
+            let block_size = std::cmp::min(self.hash_info.block_size, self.hash_info.data_size - block_offset);
+
+            let mut block = vec![0u8; block_size];
+            reader_read_at(&self.reader, self.hash_info.data_offset + block_offset as u64, &mut block)?;
+            if Sha256::digest(&block).as_slice() != &self.hash_table[block_idx * 0x20..(block_idx + 1) * 0x20] {
+                return Err(Error::new(ErrorKind::InvalidData, format!("Hash mismatch for block {} (offset {:#X})", block_idx, block_offset)));
+            }
+
+            self.cur_block = Some((block_idx, block));
+        }
+
+        Ok(&self.cur_block.as_ref().unwrap().1)
+    }
+}
+
+impl Read for Sha256VerifiedReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if (self.offset >= self.hash_info.data_size as u64) || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let block_idx = (self.offset / self.hash_info.block_size as u64) as usize;
+        let block_offset = (self.offset % self.hash_info.block_size as u64) as usize;
+        let block = self.load_block(block_idx)?;
+
+        let read_size = std::cmp::min(buf.len(), block.len() - block_offset);
+        buf[..read_size].copy_from_slice(&block[block_offset..block_offset + read_size]);
+        self.offset += read_size as u64;
+        Ok(read_size)
+    }
+}
+
+impl Seek for Sha256VerifiedReader {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let new_offset = match pos {
+            SeekFrom::Start(start_pos) => Some(start_pos),
+            SeekFrom::Current(cur_pos) => self.offset.checked_add_signed(cur_pos),
+            SeekFrom::End(end_pos) => (self.hash_info.data_size as u64).checked_add_signed(end_pos)
+        };
+
+        match new_offset {
+            Some(new_offset) => {
+                self.offset = new_offset;
+                Ok(self.offset)
+            },
+            None => Err(Error::new(ErrorKind::InvalidInput, "Invalid seek to a negative or overflowing position"))
+        }
+    }
+}
+
 pub struct PFS0 {
     reader: Shared<dyn ReadSeek>,
     header: Header,
     file_entries: Vec<FileEntry>,
-    string_table: Vec<u8>
+    string_table: Vec<u8>,
+    decode_mode: DecodeMode
 }
 
 impl PFS0 {
+    #[inline]
     pub fn new(reader: Shared<dyn ReadSeek>) -> Result<Self> {
-        let header: Header = reader_read_val(&reader)?;
+        Self::new_with_options(reader, DecodeMode::default())
+    }
+
+    #[inline]
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self> {
+        Self::new(new_shared(Cursor::new(data)))
+    }
+
+    pub fn new_with_options(reader: Shared<dyn ReadSeek>, decode_mode: DecodeMode) -> Result<Self> {
+        // The reader might be shared (or be a member reader of an outer container), so everything is read at explicit offsets
+        let header: Header = reader_read_val_at(&reader, 0)?;
         if header.magic != Header::MAGIC {
             return Err(Error::new(ErrorKind::InvalidInput, "Invalid PFS0 magic"));
         }
+        if header.reserved != [0; 0x4] {
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid PFS0 header (non-zero reserved field)"));
+        }
 
         let mut file_entries: Vec<FileEntry> = Vec::with_capacity(header.file_count as usize);
 
-        for _ in 0..header.file_count {
-            let file_entry: FileEntry = reader_read_val(&reader)?;
+        for i in 0..header.file_count as usize {
+            let file_entry: FileEntry = reader_read_val_at(&reader, (std::mem::size_of::<Header>() + i * std::mem::size_of::<FileEntry>()) as u64)?;
             file_entries.push(file_entry);
         }
 
+        // Unlike HFS0 entries there is no per-entry hash/padding, so file data must start right after the string table
+        if let Some(first_file_entry) = file_entries.first() {
+            if first_file_entry.offset != 0 {
+                return Err(Error::new(ErrorKind::InvalidData, format!("First PFS0 file data is not aligned with the data start (offset {:#X})", first_file_entry.offset)));
+            }
+        }
+
         let mut str_table = vec![0u8; header.string_table_size as usize];
-        reader.lock().unwrap().read_exact(&mut str_table)?;
+        reader_read_at(&reader, (std::mem::size_of::<Header>() + file_entries.len() * std::mem::size_of::<FileEntry>()) as u64, &mut str_table)?;
 
         Ok(Self {
-            reader: reader,
-            header: header,
-            file_entries: file_entries,
-            string_table: str_table
+            reader,
+            header,
+            file_entries,
+            string_table: str_table,
+            decode_mode
         })
     }
 
+    #[inline]
+    pub fn get_file_count(&self) -> usize {
+        self.header.file_count as usize
+    }
+
+    #[inline]
+    pub fn get_string_table_size(&self) -> usize {
+        self.header.string_table_size as usize
+    }
+
+    #[inline]
+    pub fn get_data_offset(&self) -> u64 {
+        (std::mem::size_of::<Header>() + std::mem::size_of::<FileEntry>() * self.header.file_count as usize + self.header.string_table_size as usize) as u64
+    }
+
+    fn get_file_name_bytes(&self, entry: &FileEntry) -> &[u8] {
+        let str_t = self.string_table.get(entry.string_table_offset as usize..).unwrap_or(&[]);
+        let str_len = str_t.iter().position(|chr| *chr == 0).unwrap_or(str_t.len());
+        &str_t[..str_len]
+    }
+
     pub fn list_files(&self) -> Result<Vec<String>> {
         let mut file_names: Vec<String> = Vec::with_capacity(self.file_entries.len());
 
         for entry in self.file_entries.iter() {
-            let mut bytes: Vec<u8> = Vec::new();
+            let bytes = self.get_file_name_bytes(entry).to_vec();
+            file_names.push(decode_name(bytes, self.decode_mode)?);
+        }
 
-            let str_t = &self.string_table[entry.string_table_offset as usize..];
-            for i in 0..str_t.len() {
-                if str_t[i] == 0 {
-                    break;
-                }
+        Ok(file_names)
+    }
 
-                bytes.push(str_t[i]);
-            }
+    // Names are compared as raw bytes, so this works regardless of the decode mode
+    pub fn find_file(&self, name: &str) -> Option<usize> {
+        self.file_entries.iter().position(|entry| self.get_file_name_bytes(entry) == name.as_bytes())
+    }
 
-            file_names.push(String::from_utf8(bytes).unwrap());
-        }
+    #[inline]
+    pub fn get_file_entry(&self, name: &str) -> Option<FileEntry> {
+        self.find_file(name).map(|idx| self.file_entries[idx])
+    }
 
-        Ok(file_names)
+    fn check_file_index(&self, idx: usize) -> Result<()> {
+        match idx < self.file_entries.len() {
+            true => Ok(()),
+            false => Err(Error::new(ErrorKind::InvalidInput, format!("Invalid file index {} (file count: {})", idx, self.file_entries.len())))
+        }
     }
 
     pub fn get_file_size(&mut self, idx: usize) -> Result<usize> {
-        if idx >= self.file_entries.len() {
-            return Err(Error::new(ErrorKind::InvalidInput, "Invalid file index"));
-        }
+        self.check_file_index(idx)?;
 
         Ok(self.file_entries[idx].size)
     }
 
     pub fn read_file(&mut self, idx: usize, offset: usize, buf: &mut [u8]) -> Result<usize> {
-        if idx >= self.file_entries.len() {
-            return Err(Error::new(ErrorKind::InvalidInput, "Invalid file index"));
-        }
+        self.check_file_index(idx)?;
 
         let entry = &self.file_entries[idx];
         if (offset + buf.len()) > entry.size {
-            return Err(Error::new(ErrorKind::UnexpectedEof, "EOF reached"));
+            return Err(Error::new(ErrorKind::UnexpectedEof, format!("Read range (offset {:#X}, size {:#X}) exceeds the file size ({:#X})", offset, buf.len(), entry.size)));
+        }
+
+        // The whole range is within the file, so it's either fully read or an error
+        let read_offset = self.get_file_data_offset(idx) + offset as u64;
+        reader_read_at(&self.reader, read_offset, buf)?;
+        Ok(buf.len())
+    }
+
+    fn get_file_data_offset(&self, idx: usize) -> u64 {
+        self.get_data_offset() + self.file_entries[idx].offset
+    }
+
+    // The returned reader is bounded to the file (Read + Seek), so it can be streamed with io::copy and similar
+    pub fn open_file(&mut self, idx: usize) -> Result<SubReader> {
+        self.check_file_index(idx)?;
+
+        Ok(SubReader::new(self.reader.clone(), self.get_file_data_offset(idx), self.file_entries[idx].size as u64))
+    }
+
+    #[inline]
+    pub fn file_sha256(&mut self, idx: usize) -> Result<[u8; 0x20]> {
+        hash_reader(&mut self.open_file(idx)?)
+    }
+
+    // Members are bounded readers, so offsets inside the nested PFS0 are relative to the member itself
+    #[inline]
+    pub fn open_pfs0(&mut self, idx: usize) -> Result<PFS0> {
+        PFS0::new_with_options(new_shared(self.open_file(idx)?), self.decode_mode)
+    }
+
+    fn extract_file_to(&mut self, out_dir: &Path, idx: usize, file_name: &str) -> Result<()> {
+        let out_path = get_member_out_path(out_dir, file_name)?;
+        if let Some(out_parent_dir) = out_path.parent() {
+            create_dir_all(out_parent_dir)?;
         }
 
-        let base_offset = std::mem::size_of::<Header>() + std::mem::size_of::<FileEntry>() * self.header.file_count as usize + self.header.string_table_size as usize;
-        let base_read_offset = base_offset + entry.offset as usize;
-        let read_offset = base_read_offset + offset;
+        let mut file_reader = self.open_file(idx)?;
+        let file_size = file_reader.get_size();
+        let mut out_file = File::create(&out_path)?;
+        let copied_size = copy_ranged(&mut file_reader, &mut out_file, file_size, DEFAULT_COPY_CHUNK_SIZE, None)?;
+        if copied_size != file_size {
+            return Err(Error::new(ErrorKind::UnexpectedEof, format!("Only {:#X} of {:#X} bytes could be read", copied_size, file_size)));
+        }
+
+        Ok(())
+    }
+
+    // Files are streamed in chunks (never fully loaded), errors include the name of the file they happened at
+    pub fn extract_to(&mut self, out_dir: &Path) -> Result<()> {
+        create_dir_all(out_dir)?;
 
-        self.reader.lock().unwrap().seek(SeekFrom::Start(read_offset as u64))?;
-        self.reader.lock().unwrap().read(buf)
+        for (idx, file_name) in self.list_files()?.iter().enumerate() {
+            self.extract_file_to(out_dir, idx, file_name).map_err(|err| Error::new(err.kind(), format!("Failed to extract '{}': {}", file_name, err)))?;
+        }
+
+        Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[derive(Default)]
+pub struct PFS0Builder {
+    files: Vec<(String, Box<dyn Read>)>
+}
+
+impl PFS0Builder {
+    const DATA_ALIGNMENT: usize = 0x20;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Files are laid out in the order they are added
+    pub fn add_file<R: Read + 'static>(&mut self, name: &str, reader: R) -> Result<()> {
+        if name.is_empty() || name.contains('\0') {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid PFS0 file name: '{}'", name)));
+        }
+        if self.files.iter().any(|(file_name, _)| file_name == name) {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("PFS0 file already added: '{}'", name)));
+        }
+
+        self.files.push((String::from(name), Box::new(reader)));
+        Ok(())
+    }
+
+    // Layout: header, file entries, string table (zero-padded so that the data starts 0x20-aligned) and the contiguous file data
+    pub fn write_to<W: Write + Seek>(self, mut writer: W) -> Result<()> {
+        let base_offset = writer.stream_position()?;
+
+        let mut string_table: Vec<u8> = Vec::new();
+        let mut file_entries: Vec<FileEntry> = Vec::with_capacity(self.files.len());
+        for (file_name, _) in self.files.iter() {
+            file_entries.push(FileEntry {
+                offset: 0,
+                size: 0,
+                string_table_offset: string_table.len() as u32,
+                reserved: [0; 0x4]
+            });
+            string_table.extend_from_slice(file_name.as_bytes());
+            string_table.push(0);
+        }
+
+        let entries_end_offset = std::mem::size_of::<Header>() + std::mem::size_of::<FileEntry>() * file_entries.len();
+        string_table.resize(align_up(entries_end_offset + string_table.len(), Self::DATA_ALIGNMENT) - entries_end_offset, 0);
+
+        // File sizes are only known after copying their data, so the header region gets written last
+        writer.seek(SeekFrom::Start(base_offset + (entries_end_offset + string_table.len()) as u64))?;
+
+        let mut file_data_size: u64 = 0;
+        for (file_entry, (_, mut file_reader)) in file_entries.iter_mut().zip(self.files) {
+            let data_size = std::io::copy(&mut file_reader, &mut writer)?;
+            file_entry.offset = file_data_size;
+            file_entry.size = data_size as usize;
+            file_data_size += data_size;
+        }
+
+        let header = Header {
+            magic: Header::MAGIC,
+            file_count: file_entries.len() as u32,
+            string_table_size: string_table.len() as u32,
+            reserved: [0; 0x4]
+        };
+
+        let end_offset = writer.stream_position()?;
+        writer.seek(SeekFrom::Start(base_offset))?;
+        writer_write_val(&mut writer, &header)?;
+        for file_entry in file_entries.iter() {
+            writer_write_val(&mut writer, file_entry)?;
+        }
+        writer.write_all(&string_table)?;
+        writer.seek(SeekFrom::Start(end_offset))?;
+        Ok(())
+    }
+}
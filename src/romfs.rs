@@ -1,6 +1,11 @@
-use std::io::{Error, Result, ErrorKind, SeekFrom};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, create_dir_all, read_dir};
+use std::io::{Cursor, Error, Read, Result, ErrorKind, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-use crate::util::{ReadSeek, Shared, reader_read_val};
+use crate::util::{DEFAULT_COPY_CHUNK_SIZE, DecodeMode, PlainData, ReadSeek, Shared, SubReader, align_up, copy_ranged, decode_name, get_member_out_path, hash_reader, new_shared, reader_read_at, reader_read_val_at, writer_write_val};
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
 #[repr(C)]
@@ -17,6 +22,8 @@ pub struct Header {
     file_data_offset: u64
 }
 
+unsafe impl PlainData for Header {}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
 #[repr(C)]
 pub struct DirectoryInfo {
@@ -28,6 +35,8 @@ pub struct DirectoryInfo {
     name_len: u32
 }
 
+unsafe impl PlainData for DirectoryInfo {}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
 #[repr(C)]
 pub struct FileInfo {
@@ -39,40 +48,257 @@ pub struct FileInfo {
     name_len: u32
 }
 
-fn read_dir_info(reader: &Shared<dyn ReadSeek>, dir_table_offset: u64, offset: u32, read_str: bool) -> Result<(DirectoryInfo, String)> {
-    reader.lock().unwrap().seek(SeekFrom::Start(dir_table_offset + offset as u64))?;
-    let dir_info: DirectoryInfo = reader_read_val(&reader)?;
+unsafe impl PlainData for FileInfo {}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct IvfcLevelInfo {
+    pub offset: u64,
+    pub size: usize,
+    pub block_size: usize
+}
+
+// The last level holds the actual RomFs, every other one the hashes of the next level's blocks
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct IvfcHashInfo {
+    pub master_hash: [u8; 0x20],
+    pub levels: Vec<IvfcLevelInfo>
+}
+
+pub fn verify_with_ivfc(reader: Shared<dyn ReadSeek>, hash_info: &IvfcHashInfo) -> Result<bool> {
+    let first_level = match hash_info.levels.first() {
+        Some(level) => level,
+        None => return Err(Error::new(ErrorKind::InvalidInput, "No IVFC levels"))
+    };
+
+    // The master hash covers the whole first level
+    let mut hashes = vec![0u8; first_level.size];
+    {
+        let mut reader = reader.lock().unwrap();
+        reader.seek(SeekFrom::Start(first_level.offset))?;
+        reader.read_exact(&mut hashes)?;
+    }
+    if Sha256::digest(&hashes).as_slice() != hash_info.master_hash {
+        return Ok(false);
+    }
+
+    for (level_idx, level) in hash_info.levels.iter().enumerate().skip(1) {
+        if level.block_size == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid IVFC block size"));
+        }
+
+        let block_count = level.size.div_ceil(level.block_size);
+        if block_count * 0x20 > hashes.len() {
+            return Ok(false);
+        }
+
+        // Only hash levels are kept around (the last level is the actual RomFs data)
+        let is_hash_level = level_idx < hash_info.levels.len() - 1;
+        let mut level_hashes: Vec<u8> = Vec::new();
+
+        let mut block = vec![0u8; level.block_size];
+        for (block_idx, block_hash) in hashes.chunks_exact(0x20).take(block_count).enumerate() {
+            let block_offset = block_idx * level.block_size;
+            let block_size = std::cmp::min(level.block_size, level.size - block_offset);
+
+            {
+                let mut reader = reader.lock().unwrap();
+                reader.seek(SeekFrom::Start(level.offset + block_offset as u64))?;
+                reader.read_exact(&mut block[..block_size])?;
+            }
+
+            // Unlike HierarchicalSha256, partial blocks are hashed zero-padded
+            block[block_size..].fill(0);
+            if Sha256::digest(&block).as_slice() != block_hash {
+                return Ok(false);
+            }
+
+            if is_hash_level {
+                level_hashes.extend_from_slice(&block[..block_size]);
+            }
+        }
+
+        hashes = level_hashes;
+    }
+
+    Ok(true)
+}
+
+// Reads the last IVFC level (the RomFs itself), verifying every block up the tree to the (already verified) first level
+pub struct IvfcVerifiedReader {
+    reader: Shared<dyn ReadSeek>,
+    hash_info: IvfcHashInfo,
+    first_level: Vec<u8>,
+    // The last verified block of each level, since most reads are small and sequential
+    block_cache: Vec<Option<(usize, Vec<u8>)>>,
+    offset: u64
+}
+
+impl IvfcVerifiedReader {
+    pub fn new(reader: Shared<dyn ReadSeek>, hash_info: IvfcHashInfo) -> Result<Self> {
+        if hash_info.levels.len() < 2 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Not enough IVFC levels"));
+        }
+        if hash_info.levels.iter().any(|level| level.block_size == 0) {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid IVFC block size"));
+        }
+
+        let mut first_level = vec![0u8; hash_info.levels[0].size];
+        reader_read_at(&reader, hash_info.levels[0].offset, &mut first_level)?;
+        if Sha256::digest(&first_level).as_slice() != hash_info.master_hash {
+            return Err(Error::new(ErrorKind::InvalidData, "First IVFC level does not match the master hash"));
+        }
+
+        let level_count = hash_info.levels.len();
+        Ok(Self {
+            reader,
+            hash_info,
+            first_level,
+            block_cache: vec![None; level_count],
+            offset: 0
+        })
+    }
+
+    fn load_block(&mut self, level_idx: usize, block_idx: usize) -> Result<()> {
+        if matches!(&self.block_cache[level_idx], Some((cached_block_idx, _)) if *cached_block_idx == block_idx) {
+            return Ok(());
+        }
+
+        let level = self.hash_info.levels[level_idx];
+        let block_offset = block_idx * level.block_size;
+        if block_offset >= level.size {
+            return Err(Error::new(ErrorKind::InvalidData, format!("IVFC block {} is out of level {}", block_idx, level_idx)));
+        }
+        let block_size = std::cmp::min(level.block_size, level.size - block_offset);
+
+        // Unlike HierarchicalSha256, partial blocks are hashed zero-padded
+        let mut block = vec![0u8; level.block_size];
+        reader_read_at(&self.reader, level.offset + block_offset as u64, &mut block[..block_size])?;
+        let block_hash = Sha256::digest(&block);
+
+        let hash_offset = block_idx * 0x20;
+        let expected_hash = if level_idx == 1 {
+            self.first_level.get(hash_offset..hash_offset + 0x20)
+        }
+        else {
+            let parent_block_size = self.hash_info.levels[level_idx - 1].block_size;
+            self.load_block(level_idx - 1, hash_offset / parent_block_size)?;
+            let parent_hash_offset = hash_offset % parent_block_size;
+            self.block_cache[level_idx - 1].as_ref().and_then(|(_, parent_block)| parent_block.get(parent_hash_offset..parent_hash_offset + 0x20))
+        };
+        if expected_hash != Some(block_hash.as_slice()) {
+            return Err(Error::new(ErrorKind::InvalidData, format!("IVFC hash mismatch for level {} block {} (offset {:#X})", level_idx, block_idx, block_offset)));
+        }
+
+        block.truncate(block_size);
+        self.block_cache[level_idx] = Some((block_idx, block));
+        Ok(())
+    }
+}
+
+impl Read for IvfcVerifiedReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let data_level_idx = self.hash_info.levels.len() - 1;
+        let data_level = self.hash_info.levels[data_level_idx];
+        if (self.offset >= data_level.size as u64) || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let block_idx = (self.offset / data_level.block_size as u64) as usize;
+        let block_offset = (self.offset % data_level.block_size as u64) as usize;
+        self.load_block(data_level_idx, block_idx)?;
+        let block = &self.block_cache[data_level_idx].as_ref().unwrap().1;
+
+        let read_size = std::cmp::min(buf.len(), block.len() - block_offset);
+        buf[..read_size].copy_from_slice(&block[block_offset..block_offset + read_size]);
+        self.offset += read_size as u64;
+        Ok(read_size)
+    }
+}
+
+impl Seek for IvfcVerifiedReader {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let data_size = self.hash_info.levels[self.hash_info.levels.len() - 1].size as u64;
+        let new_offset = match pos {
+            SeekFrom::Start(start_pos) => Some(start_pos),
+            SeekFrom::Current(cur_pos) => self.offset.checked_add_signed(cur_pos),
+            SeekFrom::End(end_pos) => data_size.checked_add_signed(end_pos)
+        };
+
+        match new_offset {
+            Some(new_offset) => {
+                self.offset = new_offset;
+                Ok(self.offset)
+            },
+            None => Err(Error::new(ErrorKind::InvalidInput, "Invalid seek to a negative or overflowing position"))
+        }
+    }
+}
+
+fn check_table_bounds(table_size: usize, offset: u32, entry_size: usize) -> Result<()> {
+    if (offset as usize + entry_size) > table_size {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Entry at offset {:#X} exceeds its table (size {:#X})", offset, table_size)));
+    }
+
+    Ok(())
+}
+
+fn read_dir_info(reader: &Shared<dyn ReadSeek>, header: &Header, offset: u32, read_str: bool) -> Result<(DirectoryInfo, Vec<u8>)> {
+    check_table_bounds(header.dir_table_size, offset, std::mem::size_of::<DirectoryInfo>())?;
+    let dir_info_offset = header.dir_table_offset + offset as u64;
+    let dir_info: DirectoryInfo = reader_read_val_at(reader, dir_info_offset)?;
 
     let name = match read_str {
         true => {
-            let mut name_data = vec![0u8; dir_info.name_len as usize]; 
-            reader.lock().unwrap().read_exact(&mut name_data)?;
-            String::from_utf8(name_data).unwrap()
+            check_table_bounds(header.dir_table_size, offset, std::mem::size_of::<DirectoryInfo>() + dir_info.name_len as usize)?;
+            let mut name_data = vec![0u8; dir_info.name_len as usize];
+            reader_read_at(reader, dir_info_offset + std::mem::size_of::<DirectoryInfo>() as u64, &mut name_data)?;
+            name_data
         },
-        false => String::new()
+        false => Vec::new()
     };
     Ok((dir_info, name))
 }
 
-fn read_file_info(reader: &Shared<dyn ReadSeek>, file_table_offset: u64, offset: u32, read_str: bool) -> Result<(FileInfo, String)> {
-    reader.lock().unwrap().seek(SeekFrom::Start(file_table_offset + offset as u64))?;
-    let file_info: FileInfo = reader_read_val(&reader)?;
+fn read_file_info(reader: &Shared<dyn ReadSeek>, header: &Header, offset: u32, read_str: bool) -> Result<(FileInfo, Vec<u8>)> {
+    check_table_bounds(header.file_table_size, offset, std::mem::size_of::<FileInfo>())?;
+    let file_info_offset = header.file_table_offset + offset as u64;
+    let file_info: FileInfo = reader_read_val_at(reader, file_info_offset)?;
 
     let name = match read_str {
         true => {
-            let mut name_data = vec![0u8; file_info.name_len as usize]; 
-            reader.lock().unwrap().read_exact(&mut name_data)?;
-            String::from_utf8(name_data).unwrap()
+            check_table_bounds(header.file_table_size, offset, std::mem::size_of::<FileInfo>() + file_info.name_len as usize)?;
+            let mut name_data = vec![0u8; file_info.name_len as usize];
+            reader_read_at(reader, file_info_offset + std::mem::size_of::<FileInfo>() as u64, &mut name_data)?;
+            name_data
         },
-        false => String::new()
+        false => Vec::new()
     };
     Ok((file_info, name))
 }
 
+// The offset is relative to the file data region, like get_file_offset / read_file_by_offset
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RomFsFileEntry {
+    pub name: String,
+    pub offset: u64,
+    pub size: usize
+}
+
+// Offsets are within the directory/file tables, RomFs::INVALID_INFO_OFFSET meaning none
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RomFsDirectoryEntry {
+    pub name: String,
+    pub offset: u32,
+    pub parent_dir_offset: u32,
+    pub sibling_dir_offset: u32,
+    pub first_child_dir_offset: u32,
+    pub first_child_file_offset: u32
+}
+
 pub struct RomFsDirectoryIterator {
     reader: Shared<dyn ReadSeek>,
-    dir_table_offset: u64,
-    file_table_offset: u64,
+    header: Header,
+    decode_mode: DecodeMode,
     dir_offsets: Vec<u32>,
     file_offsets: Vec<u32>,
     cur_dir_idx: usize,
@@ -80,11 +306,11 @@ pub struct RomFsDirectoryIterator {
 }
 
 impl RomFsDirectoryIterator {
-    pub fn new(reader: Shared<dyn ReadSeek>, dir_table_offset: u64, file_table_offset: u64, dir_offsets: Vec<u32>, file_offsets: Vec<u32>) -> Self {
+    pub fn new(reader: Shared<dyn ReadSeek>, header: Header, decode_mode: DecodeMode, dir_offsets: Vec<u32>, file_offsets: Vec<u32>) -> Self {
         Self {
             reader,
-            dir_table_offset,
-            file_table_offset,
+            header,
+            decode_mode,
             dir_offsets,
             file_offsets,
             cur_dir_idx: 0,
@@ -92,17 +318,30 @@ impl RomFsDirectoryIterator {
         }
     }
 
-    pub fn next_dir(&mut self) -> Result<String> {
+    pub fn next_dir_entry(&mut self) -> Result<RomFsDirectoryEntry> {
         if self.cur_dir_idx == self.dir_offsets.len() {
             Err(Error::new(ErrorKind::UnexpectedEof, "No more directories"))
         }
         else {
-            let (_, dir_name) = read_dir_info(&self.reader, self.dir_table_offset, self.dir_offsets[self.cur_dir_idx], true)?;
+            let dir_offset = self.dir_offsets[self.cur_dir_idx];
+            let (dir, dir_name) = read_dir_info(&self.reader, &self.header, dir_offset, true)?;
             self.cur_dir_idx += 1;
-            Ok(dir_name)
+            Ok(RomFsDirectoryEntry {
+                name: decode_name(dir_name, self.decode_mode)?,
+                offset: dir_offset,
+                parent_dir_offset: dir.parent_dir_offset,
+                sibling_dir_offset: dir.sibling_dir_offset,
+                first_child_dir_offset: dir.first_child_dir_offset,
+                first_child_file_offset: dir.first_child_file_offset
+            })
         }
     }
 
+    #[inline]
+    pub fn next_dir(&mut self) -> Result<String> {
+        self.next_dir_entry().map(|dir_entry| dir_entry.name)
+    }
+
     pub fn get_dir_count(&self) -> usize {
         self.dir_offsets.len()
     }
@@ -111,17 +350,26 @@ impl RomFsDirectoryIterator {
         self.cur_dir_idx = 0;
     }
 
-    pub fn next_file(&mut self) -> Result<(String, usize)> {
+    pub fn next_file_entry(&mut self) -> Result<RomFsFileEntry> {
         if self.cur_file_idx == self.file_offsets.len() {
             Err(Error::new(ErrorKind::UnexpectedEof, "No more files"))
         }
         else {
-            let (file, file_name) = read_file_info(&self.reader, self.file_table_offset, self.file_offsets[self.cur_file_idx], true)?;
+            let (file, file_name) = read_file_info(&self.reader, &self.header, self.file_offsets[self.cur_file_idx], true)?;
             self.cur_file_idx += 1;
-            Ok((file_name, file.data_size))
+            Ok(RomFsFileEntry {
+                name: decode_name(file_name, self.decode_mode)?,
+                offset: file.data_offset,
+                size: file.data_size
+            })
         }
     }
 
+    #[inline]
+    pub fn next_file(&mut self) -> Result<(String, usize)> {
+        self.next_file_entry().map(|file_entry| (file_entry.name, file_entry.size))
+    }
+
     pub fn get_file_count(&self) -> usize {
         self.file_offsets.len()
     }
@@ -131,9 +379,140 @@ impl RomFsDirectoryIterator {
     }
 }
 
+// Depth-first over the whole tree (each directory's files before its subdirectories), reading entries only as needed
+pub struct RomFsFileIterator {
+    reader: Shared<dyn ReadSeek>,
+    header: Header,
+    decode_mode: DecodeMode,
+    pending_dirs: Vec<(u32, String)>,
+    visited_dir_offsets: BTreeSet<u32>,
+    visited_file_offsets: BTreeSet<u32>,
+    cur_dir_path: String,
+    cur_file_offset: u32,
+    failed: bool
+}
+
+impl RomFsFileIterator {
+    fn make_path(dir_path: &str, name: String) -> String {
+        match dir_path.is_empty() {
+            true => name,
+            false => format!("{}/{}", dir_path, name)
+        }
+    }
+
+    fn next_file(&mut self) -> Result<Option<(String, usize)>> {
+        while self.cur_file_offset == RomFs::INVALID_INFO_OFFSET {
+            let (dir_offset, dir_path) = match self.pending_dirs.pop() {
+                Some(pending_dir) => pending_dir,
+                None => return Ok(None)
+            };
+
+            let (dir, _) = read_dir_info(&self.reader, &self.header, dir_offset, false)?;
+
+            let mut child_dirs: Vec<(u32, String)> = Vec::new();
+            let mut cur_dir_offset = dir.first_child_dir_offset;
+            while cur_dir_offset != RomFs::INVALID_INFO_OFFSET {
+                // Corrupted images could otherwise make us loop forever
+                if !self.visited_dir_offsets.insert(cur_dir_offset) {
+                    return Err(Error::new(ErrorKind::InvalidData, format!("Directory at offset {:#X} is linked more than once", cur_dir_offset)));
+                }
+
+                let (child_dir, child_dir_name) = read_dir_info(&self.reader, &self.header, cur_dir_offset, true)?;
+                child_dirs.push((cur_dir_offset, Self::make_path(&dir_path, decode_name(child_dir_name, self.decode_mode)?)));
+                cur_dir_offset = child_dir.sibling_dir_offset;
+            }
+
+            // Popped in reverse, so that the first child is walked first
+            self.pending_dirs.extend(child_dirs.into_iter().rev());
+            self.cur_dir_path = dir_path;
+            self.cur_file_offset = dir.first_child_file_offset;
+        }
+
+        // Every file belongs to a single dir, so no file can be visited twice
+        if !self.visited_file_offsets.insert(self.cur_file_offset) {
+            return Err(Error::new(ErrorKind::InvalidData, format!("File at offset {:#X} is linked more than once", self.cur_file_offset)));
+        }
+
+        let (file, file_name) = read_file_info(&self.reader, &self.header, self.cur_file_offset, true)?;
+        self.cur_file_offset = file.sibling_file_offset;
+        Ok(Some((Self::make_path(&self.cur_dir_path, decode_name(file_name, self.decode_mode)?), file.data_size)))
+    }
+}
+
+impl Iterator for RomFsFileIterator {
+    type Item = Result<(String, usize)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        let next_file = self.next_file();
+        self.failed = next_file.is_err();
+        next_file.transpose()
+    }
+}
+
+// Just a bounded reader over the file's data, kept as its own type since sizes are usize everywhere else in RomFs
+pub struct RomFsFileReader {
+    sub_reader: SubReader
+}
+
+impl RomFsFileReader {
+    pub fn new(reader: Shared<dyn ReadSeek>, data_offset: u64, data_size: usize) -> Self {
+        Self {
+            sub_reader: SubReader::new(reader, data_offset, data_size as u64)
+        }
+    }
+
+    #[inline]
+    pub fn get_size(&self) -> usize {
+        self.sub_reader.get_size() as usize
+    }
+}
+
+impl Read for RomFsFileReader {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.sub_reader.read(buf)
+    }
+}
+
+// Seeking past the end is allowed (like with regular files), reads there just return 0 bytes
+impl Seek for RomFsFileReader {
+    #[inline]
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.sub_reader.seek(pos)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct RomFsIndexEntry {
+    pub offset: u64,
+    pub size: usize
+}
+
+// Full path -> file data region, meant to be persisted so that lookups don't need to go through the tables
+#[derive(Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub struct RomFsIndex {
+    pub file_data_offset: u64,
+    pub files: BTreeMap<String, RomFsIndexEntry>
+}
+
+// Only used by find_file_normalized, regular lookups are always exact (like the console does)
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct PathOptions {
+    pub case_insensitive: bool,
+    pub backslash_separators: bool
+}
+
 pub struct RomFs {
     reader: Shared<dyn ReadSeek>,
-    header: Header
+    header: Header,
+    data_end_offset: u64,
+    decode_mode: DecodeMode,
+    index: Option<RomFsIndex>,
+    path_options: PathOptions
 }
 
 impl RomFs {
@@ -143,7 +522,7 @@ impl RomFs {
     fn compute_hash(parent_offset: u32, name: &[u8], hash_table_count: usize) -> u32 {
         let mut hash = parent_offset ^ 123456789;
         for name_chr in name {
-            hash = (hash >> 5) | (hash << 27);
+            hash = hash.rotate_right(5);
             hash ^= *name_chr as u32;
         }
 
@@ -151,23 +530,27 @@ impl RomFs {
     }
 
     fn read_dir_offset(&mut self, hash: u32) -> Result<u32> {
-        self.reader.lock().unwrap().seek(SeekFrom::Start(self.header.dir_hash_table_offset + hash as u64 * std::mem::size_of::<u32>() as u64))?;
-        reader_read_val(&self.reader)
+        reader_read_val_at(&self.reader, self.header.dir_hash_table_offset + hash as u64 * std::mem::size_of::<u32>() as u64)
     }
 
     fn read_file_offset(&mut self, hash: u32) -> Result<u32> {
-        self.reader.lock().unwrap().seek(SeekFrom::Start(self.header.file_hash_table_offset + hash as u64 * std::mem::size_of::<u32>() as u64))?;
-        reader_read_val(&self.reader)
+        reader_read_val_at(&self.reader, self.header.file_hash_table_offset + hash as u64 * std::mem::size_of::<u32>() as u64)
     }
 
     fn find_dir_offset(&mut self, parent_dir_offset: u32, name: String) -> Result<u32> {
         let hash = Self::compute_hash(parent_dir_offset, name.as_bytes(), self.header.dir_hash_table_size / std::mem::size_of::<u32>());
         let first_dir_offset = self.read_dir_offset(hash)?;
 
+        let mut visited_dir_offsets: BTreeSet<u32> = BTreeSet::new();
         let mut cur_dir_offset = first_dir_offset;
         while cur_dir_offset != Self::INVALID_INFO_OFFSET {
-            let (dir, dir_name) = read_dir_info(&self.reader, self.header.dir_table_offset, cur_dir_offset, true)?;
-            if dir.parent_dir_offset == parent_dir_offset && dir_name == name {
+            // Corrupted images could otherwise make us loop forever
+            if !visited_dir_offsets.insert(cur_dir_offset) {
+                return Err(Error::new(ErrorKind::InvalidData, format!("Directory at offset {:#X} is linked more than once in its hash chain", cur_dir_offset)));
+            }
+
+            let (dir, dir_name) = read_dir_info(&self.reader, &self.header, cur_dir_offset, true)?;
+            if dir.parent_dir_offset == parent_dir_offset && dir_name == name.as_bytes() {
                 return Ok(cur_dir_offset);
             }
 
@@ -181,10 +564,15 @@ impl RomFs {
         let hash = Self::compute_hash(parent_dir_offset, name.as_bytes(), self.header.file_hash_table_size / std::mem::size_of::<u32>());
         let first_dir_offset = self.read_file_offset(hash)?;
 
+        let mut visited_file_offsets: BTreeSet<u32> = BTreeSet::new();
         let mut cur_file_offset = first_dir_offset;
         while cur_file_offset != Self::INVALID_INFO_OFFSET {
-            let (file, file_name) = read_file_info(&self.reader, self.header.file_table_offset, cur_file_offset, true)?;
-            if file.parent_dir_offset == parent_dir_offset && file_name == name {
+            if !visited_file_offsets.insert(cur_file_offset) {
+                return Err(Error::new(ErrorKind::InvalidData, format!("File at offset {:#X} is linked more than once in its hash chain", cur_file_offset)));
+            }
+
+            let (file, file_name) = read_file_info(&self.reader, &self.header, cur_file_offset, true)?;
+            if file.parent_dir_offset == parent_dir_offset && file_name == name.as_bytes() {
                 return Ok(file);
             }
 
@@ -194,27 +582,129 @@ impl RomFs {
         Err(Error::new(ErrorKind::NotFound, "File not found"))
     }
 
+    fn check_file_data_range(&self, data_offset: u64, data_size: u64) -> Result<()> {
+        let data_end_offset = self.header.file_data_offset.checked_add(data_offset).and_then(|offset| offset.checked_add(data_size));
+        match data_end_offset {
+            Some(data_end_offset) if data_end_offset <= self.data_end_offset => Ok(()),
+            _ => Err(Error::new(ErrorKind::InvalidData, format!("File data range (offset {:#X}, size {:#X}) exceeds the RomFs image", data_offset, data_size)))
+        }
+    }
+
+    // Leading/trailing/repeated slashes are ignored, so "/a.txt", "a.txt" and "dir//b.txt" work as expected
+    fn get_path_items(path: &str) -> Vec<&str> {
+        path.split('/').filter(|item| !item.is_empty()).collect()
+    }
+
     fn find_file(&mut self, path: String) -> Result<FileInfo> {
-        let mut path_items: Vec<_> = path.split("/").collect();
-        let file_item = path_items.pop().unwrap();
+        let path_items = Self::get_path_items(&path);
+        let (file_item, dir_items) = match path_items.split_last() {
+            Some(path_items) => path_items,
+            None => return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid file path: '{}'", path)))
+        };
+
+        if let Some(index) = self.index.as_ref() {
+            let file_info = match index.files.get(&path_items.join("/")) {
+                Some(entry) => FileInfo { data_offset: entry.offset, data_size: entry.size, ..Default::default() },
+                None => return Err(Error::new(ErrorKind::NotFound, "File not found"))
+            };
+
+            self.check_file_data_range(file_info.data_offset, file_info.data_size as u64)?;
+            return Ok(file_info);
+        }
 
         let mut cur_dir_offset = Self::ROOT_DIR_OFFSET;
-        for dir_item in path_items {
-            cur_dir_offset = self.find_dir_offset(cur_dir_offset, String::from(dir_item))?;
+        for dir_item in dir_items {
+            cur_dir_offset = self.find_dir_offset(cur_dir_offset, String::from(*dir_item))?;
         }
 
-        self.find_file_info(cur_dir_offset, String::from(file_item))
+        let file_info = self.find_file_info(cur_dir_offset, String::from(*file_item))?;
+        self.check_file_data_range(file_info.data_offset, file_info.data_size as u64)?;
+        Ok(file_info)
     }
 
+    // Names are hashed case-sensitively, so case-insensitive lookups need to go through every child
+    fn find_child_dir_ignore_case(&mut self, parent_dir: &DirectoryInfo, name: &str) -> Result<(u32, String)> {
+        let mut visited_dir_offsets: BTreeSet<u32> = BTreeSet::new();
+        let mut cur_dir_offset = parent_dir.first_child_dir_offset;
+        while (cur_dir_offset != Self::INVALID_INFO_OFFSET) && visited_dir_offsets.insert(cur_dir_offset) {
+            let (dir, dir_name) = read_dir_info(&self.reader, &self.header, cur_dir_offset, true)?;
+            let dir_name = decode_name(dir_name, self.decode_mode)?;
+            if dir_name.to_lowercase() == name.to_lowercase() {
+                return Ok((cur_dir_offset, dir_name));
+            }
+
+            cur_dir_offset = dir.sibling_dir_offset;
+        }
+
+        Err(Error::new(ErrorKind::NotFound, "Directory not found"))
+    }
+
+    fn find_child_file_ignore_case(&mut self, parent_dir: &DirectoryInfo, name: &str) -> Result<String> {
+        let mut visited_file_offsets: BTreeSet<u32> = BTreeSet::new();
+        let mut cur_file_offset = parent_dir.first_child_file_offset;
+        while (cur_file_offset != Self::INVALID_INFO_OFFSET) && visited_file_offsets.insert(cur_file_offset) {
+            let (file, file_name) = read_file_info(&self.reader, &self.header, cur_file_offset, true)?;
+            let file_name = decode_name(file_name, self.decode_mode)?;
+            if file_name.to_lowercase() == name.to_lowercase() {
+                return Ok(file_name);
+            }
+
+            cur_file_offset = file.sibling_file_offset;
+        }
+
+        Err(Error::new(ErrorKind::NotFound, "File not found"))
+    }
+
+    #[inline]
+    pub fn get_path_options(&self) -> PathOptions {
+        self.path_options
+    }
+
+    #[inline]
+    pub fn set_path_options(&mut self, path_options: PathOptions) {
+        self.path_options = path_options;
+    }
+
+    // Returns the actual (exact) path of the file, to be used with the rest of the functions
+    pub fn find_file_normalized(&mut self, path: String) -> Result<String> {
+        let path = match self.path_options.backslash_separators {
+            true => path.replace('\\', "/"),
+            false => path
+        };
+
+        if !self.path_options.case_insensitive {
+            self.find_file(path.clone())?;
+            return Ok(Self::get_path_items(&path).join("/"));
+        }
+
+        let path_items = Self::get_path_items(&path);
+        let (file_item, dir_items) = match path_items.split_last() {
+            Some(path_items) => path_items,
+            None => return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid file path: '{}'", path)))
+        };
+
+        let mut actual_path_items: Vec<String> = Vec::with_capacity(path_items.len());
+        let (mut cur_dir, _) = read_dir_info(&self.reader, &self.header, Self::ROOT_DIR_OFFSET, false)?;
+        for dir_item in dir_items {
+            let (dir_offset, dir_name) = self.find_child_dir_ignore_case(&cur_dir, dir_item)?;
+            (cur_dir, _) = read_dir_info(&self.reader, &self.header, dir_offset, false)?;
+            actual_path_items.push(dir_name);
+        }
+        actual_path_items.push(self.find_child_file_ignore_case(&cur_dir, file_item)?);
+
+        Ok(actual_path_items.join("/"))
+    }
+
+    // An empty path (or just slashes) is the root dir
     fn find_dir(&mut self, path: String) -> Result<DirectoryInfo> {
-        let path_items: Vec<_> = path.split("/").collect();
+        let path_items = Self::get_path_items(&path);
 
         let mut cur_dir_offset = Self::ROOT_DIR_OFFSET;
         for dir_item in path_items {
             cur_dir_offset = self.find_dir_offset(cur_dir_offset, String::from(dir_item))?;
         }
 
-        let (dir, _) = read_dir_info(&self.reader, self.header.dir_table_offset, cur_dir_offset, false)?;
+        let (dir, _) = read_dir_info(&self.reader, &self.header, cur_dir_offset, false)?;
         Ok(dir)
     }
 
@@ -236,17 +726,236 @@ impl RomFs {
         Ok(file_info.data_offset)
     }
 
-    pub fn read_file_by_offset(&mut self, file_offset: u64, offset: u64, buf: &mut [u8]) -> Result<usize> {
+    // Like PFS0::read_file, the whole range must be within the file (and the file data within the image)
+    // The whole buffer is always filled (short reads from the underlying reader, like the sector-aligned AES-CTR ones, are retried) or an error is returned
+    pub fn read_file_by_offset(&mut self, file_offset: u64, file_size: usize, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        if offset.checked_add(buf.len() as u64).is_none_or(|read_end_offset| read_end_offset > file_size as u64) {
+            return Err(Error::new(ErrorKind::UnexpectedEof, format!("Read range (offset {:#X}, size {:#X}) exceeds the file size ({:#X})", offset, buf.len(), file_size)));
+        }
+
+        self.check_file_data_range(file_offset, offset + buf.len() as u64)?;
         let file_data_offset = self.header.file_data_offset + file_offset;
         let read_offset = file_data_offset + offset;
-        self.reader.lock().unwrap().seek(SeekFrom::Start(read_offset))?;
-        self.reader.lock().unwrap().read(buf)
+        reader_read_at(&self.reader, read_offset, buf)?;
+        Ok(buf.len())
     }
-    
+
     #[inline]
     pub fn read_file(&mut self, path: String, offset: u64, buf: &mut [u8]) -> Result<usize> {
-        let file_offset = self.get_file_offset(path)?;
-        self.read_file_by_offset(file_offset, offset, buf)
+        let file_info = self.find_file(path)?;
+        self.read_file_by_offset(file_info.data_offset, file_info.data_size, offset, buf)
+    }
+
+    #[inline]
+    pub fn read_file_entry(&mut self, file_entry: &RomFsFileEntry, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        self.read_file_by_offset(file_entry.offset, file_entry.size, offset, buf)
+    }
+
+    pub fn read_range(&mut self, path: String, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let file_info = self.find_file(path)?;
+
+        let available_size = (file_info.data_size as u64).saturating_sub(offset);
+        let mut data = vec![0u8; std::cmp::min(len as u64, available_size) as usize];
+
+        let mut reader = self.reader.lock().unwrap();
+        reader.seek(SeekFrom::Start(self.header.file_data_offset + file_info.data_offset + offset))?;
+        reader.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    pub fn list_unique_data_regions(&mut self) -> Result<Vec<(u64, usize)>> {
+        let mut data_regions: BTreeSet<(u64, usize)> = BTreeSet::new();
+
+        // File entries are stored contiguously, so just walk the whole table
+        let mut cur_file_offset: usize = 0;
+        while cur_file_offset < self.header.file_table_size {
+            let file_offset = match u32::try_from(cur_file_offset) {
+                Ok(file_offset) => file_offset,
+                Err(_) => return Err(Error::new(ErrorKind::InvalidData, format!("File entry offset {:#X} is out of range", cur_file_offset)))
+            };
+            let (file, _) = read_file_info(&self.reader, &self.header, file_offset, false)?;
+            data_regions.insert((file.data_offset, file.data_size));
+
+            // Corrupted name lengths could otherwise make us loop forever or walk past the table
+            let entry_size = std::mem::size_of::<FileInfo>() + align_up(file.name_len as usize, 4);
+            check_table_bounds(self.header.file_table_size, file_offset, entry_size)?;
+            cur_file_offset = match cur_file_offset.checked_add(entry_size) {
+                Some(next_file_offset) if next_file_offset > cur_file_offset => next_file_offset,
+                _ => return Err(Error::new(ErrorKind::InvalidData, format!("File entry at offset {:#X} has an invalid size", file_offset)))
+            };
+        }
+
+        Ok(data_regions.into_iter().collect())
+    }
+
+    fn list_child_dirs(&mut self, first_child_dir_offset: u32, parent_path: &str, visited_dir_offsets: &mut BTreeSet<u32>, dir_paths: &mut Vec<String>) -> Result<()> {
+        let mut cur_dir_offset = first_child_dir_offset;
+        while cur_dir_offset != Self::INVALID_INFO_OFFSET {
+            // Corrupted images could otherwise make us loop forever
+            if !visited_dir_offsets.insert(cur_dir_offset) {
+                return Err(Error::new(ErrorKind::InvalidData, format!("Directory at offset {:#X} is linked more than once", cur_dir_offset)));
+            }
+
+            let (dir, dir_name) = read_dir_info(&self.reader, &self.header, cur_dir_offset, true)?;
+            let dir_name = decode_name(dir_name, self.decode_mode)?;
+            let dir_path = match parent_path.is_empty() {
+                true => dir_name,
+                false => format!("{}/{}", parent_path, dir_name)
+            };
+
+            dir_paths.push(dir_path.clone());
+            self.list_child_dirs(dir.first_child_dir_offset, &dir_path, visited_dir_offsets, dir_paths)?;
+            cur_dir_offset = dir.sibling_dir_offset;
+        }
+
+        Ok(())
+    }
+
+    // The root dir itself is not included
+    pub fn list_all_dirs(&mut self) -> Result<Vec<String>> {
+        let (root_dir, _) = read_dir_info(&self.reader, &self.header, Self::ROOT_DIR_OFFSET, false)?;
+
+        let mut visited_dir_offsets: BTreeSet<u32> = BTreeSet::new();
+        visited_dir_offsets.insert(Self::ROOT_DIR_OFFSET);
+        let mut dir_paths: Vec<String> = Vec::new();
+        self.list_child_dirs(root_dir.first_child_dir_offset, "", &mut visited_dir_offsets, &mut dir_paths)?;
+        Ok(dir_paths)
+    }
+
+    fn collect_files(&mut self, dir: DirectoryInfo, dir_path: &str, visited_dir_offsets: &mut BTreeSet<u32>, files: &mut Vec<(String, FileInfo)>) -> Result<()> {
+        let make_path = |name: String| match dir_path.is_empty() {
+            true => name,
+            false => format!("{}/{}", dir_path, name)
+        };
+
+        let mut visited_file_offsets: BTreeSet<u32> = BTreeSet::new();
+        let mut cur_file_offset = dir.first_child_file_offset;
+        while cur_file_offset != Self::INVALID_INFO_OFFSET {
+            if !visited_file_offsets.insert(cur_file_offset) {
+                return Err(Error::new(ErrorKind::InvalidData, format!("File at offset {:#X} is linked more than once", cur_file_offset)));
+            }
+
+            let (file, file_name) = read_file_info(&self.reader, &self.header, cur_file_offset, true)?;
+            files.push((make_path(decode_name(file_name, self.decode_mode)?), file));
+            cur_file_offset = file.sibling_file_offset;
+        }
+
+        let mut cur_dir_offset = dir.first_child_dir_offset;
+        while cur_dir_offset != Self::INVALID_INFO_OFFSET {
+            if !visited_dir_offsets.insert(cur_dir_offset) {
+                return Err(Error::new(ErrorKind::InvalidData, format!("Directory at offset {:#X} is linked more than once", cur_dir_offset)));
+            }
+
+            let (child_dir, child_dir_name) = read_dir_info(&self.reader, &self.header, cur_dir_offset, true)?;
+            let child_dir_path = make_path(decode_name(child_dir_name, self.decode_mode)?);
+            self.collect_files(child_dir, &child_dir_path, visited_dir_offsets, files)?;
+            cur_dir_offset = child_dir.sibling_dir_offset;
+        }
+
+        Ok(())
+    }
+
+    // Returned paths are full paths (not relative to the prefix), an empty prefix meaning the whole RomFs
+    pub fn read_subtree(&mut self, prefix: String) -> Result<Vec<(String, Vec<u8>)>> {
+        let dir_path = prefix.trim_matches('/');
+        let dir = self.find_dir(String::from(dir_path))?;
+
+        let mut files: Vec<(String, FileInfo)> = Vec::new();
+        self.collect_files(dir, dir_path, &mut BTreeSet::new(), &mut files)?;
+
+        let mut file_contents: Vec<(String, Vec<u8>)> = Vec::with_capacity(files.len());
+        for (file_path, file) in files {
+            self.check_file_data_range(file.data_offset, file.data_size as u64)?;
+
+            let mut file_data = vec![0u8; file.data_size];
+            let mut reader = self.reader.lock().unwrap();
+            reader.seek(SeekFrom::Start(self.header.file_data_offset + file.data_offset))?;
+            reader.read_exact(&mut file_data)?;
+            file_contents.push((file_path, file_data));
+        }
+
+        Ok(file_contents)
+    }
+
+    // Paths are full paths (like the ones taken by open_file), the iteration stops after the first error
+    pub fn iter_all_files(&self) -> RomFsFileIterator {
+        RomFsFileIterator {
+            reader: self.reader.clone(),
+            header: self.header,
+            decode_mode: self.decode_mode,
+            pending_dirs: vec![(Self::ROOT_DIR_OFFSET, String::new())],
+            visited_dir_offsets: BTreeSet::from([Self::ROOT_DIR_OFFSET]),
+            visited_file_offsets: BTreeSet::new(),
+            cur_dir_path: String::new(),
+            cur_file_offset: Self::INVALID_INFO_OFFSET,
+            failed: false
+        }
+    }
+
+    pub fn export_index(&mut self) -> Result<RomFsIndex> {
+        let (root_dir, _) = read_dir_info(&self.reader, &self.header, Self::ROOT_DIR_OFFSET, false)?;
+
+        let mut files: Vec<(String, FileInfo)> = Vec::new();
+        self.collect_files(root_dir, "", &mut BTreeSet::new(), &mut files)?;
+
+        Ok(RomFsIndex {
+            file_data_offset: self.header.file_data_offset,
+            files: files.into_iter().map(|(file_path, file)| (file_path, RomFsIndexEntry { offset: file.data_offset, size: file.data_size })).collect()
+        })
+    }
+
+    // Only the header is read (to catch indexes belonging to another image), directory operations still use the tables
+    pub fn from_index(reader: Shared<dyn ReadSeek>, index: RomFsIndex) -> Result<Self> {
+        let mut romfs = Self::new(reader)?;
+        if romfs.header.file_data_offset != index.file_data_offset {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("RomFs index does not match the image (file data offset {:#X}, expected {:#X})", index.file_data_offset, romfs.header.file_data_offset)));
+        }
+
+        romfs.index = Some(index);
+        Ok(romfs)
+    }
+
+    pub fn open_file(&mut self, path: String) -> Result<RomFsFileReader> {
+        let file_info = self.find_file(path)?;
+        Ok(RomFsFileReader::new(self.reader.clone(), self.header.file_data_offset + file_info.data_offset, file_info.data_size))
+    }
+
+    #[inline]
+    pub fn file_sha256(&mut self, path: String) -> Result<[u8; 0x20]> {
+        hash_reader(&mut self.open_file(path)?)
+    }
+
+    fn extract_file_to(&mut self, out_dir: &Path, file_path: &str, file_size: usize) -> Result<()> {
+        let out_path = get_member_out_path(out_dir, file_path)?;
+        if let Some(out_parent_dir) = out_path.parent() {
+            create_dir_all(out_parent_dir)?;
+        }
+
+        let mut file_reader = self.open_file(String::from(file_path))?;
+        let mut out_file = File::create(&out_path)?;
+        let copied_size = copy_ranged(&mut file_reader, &mut out_file, file_size as u64, DEFAULT_COPY_CHUNK_SIZE, None)?;
+        if copied_size != file_size as u64 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, format!("Only {:#X} of {:#X} bytes could be read", copied_size, file_size)));
+        }
+
+        Ok(())
+    }
+
+    // Empty directories are recreated too, errors include the RomFs path they happened at
+    pub fn extract_to(&mut self, out_dir: &Path) -> Result<()> {
+        let with_path = |path: &str, err: Error| Error::new(err.kind(), format!("Failed to extract '{}': {}", path, err));
+
+        create_dir_all(out_dir)?;
+        for dir_path in self.list_all_dirs()? {
+            get_member_out_path(out_dir, &dir_path).and_then(create_dir_all).map_err(|err| with_path(&dir_path, err))?;
+        }
+
+        for file in self.iter_all_files() {
+            let (file_path, file_size) = file?;
+            self.extract_file_to(out_dir, &file_path, file_size).map_err(|err| with_path(&file_path, err))?;
+        }
+
+        Ok(())
     }
 
     pub fn open_dir_iterator(&mut self, path: String) -> Result<RomFsDirectoryIterator> {
@@ -255,30 +964,314 @@ impl RomFs {
         let mut child_dir_offsets: Vec<u32> = Vec::new();
         let mut cur_child_dir_offset = dir.first_child_dir_offset;
         while cur_child_dir_offset != Self::INVALID_INFO_OFFSET {
+            if child_dir_offsets.contains(&cur_child_dir_offset) {
+                return Err(Error::new(ErrorKind::InvalidData, format!("Directory at offset {:#X} is linked more than once", cur_child_dir_offset)));
+            }
             child_dir_offsets.push(cur_child_dir_offset);
 
-            let (child_dir, _) = read_dir_info(&self.reader, self.header.dir_table_offset, cur_child_dir_offset, false)?;
+            let (child_dir, _) = read_dir_info(&self.reader, &self.header, cur_child_dir_offset, false)?;
             cur_child_dir_offset = child_dir.sibling_dir_offset;
         }
 
         let mut child_file_offsets: Vec<u32> = Vec::new();
         let mut cur_child_file_offset = dir.first_child_file_offset;
         while cur_child_file_offset != Self::INVALID_INFO_OFFSET {
+            if child_file_offsets.contains(&cur_child_file_offset) {
+                return Err(Error::new(ErrorKind::InvalidData, format!("File at offset {:#X} is linked more than once", cur_child_file_offset)));
+            }
             child_file_offsets.push(cur_child_file_offset);
 
-            let (child_file, _) = read_file_info(&self.reader, self.header.file_table_offset, cur_child_file_offset, false)?;
+            let (child_file, _) = read_file_info(&self.reader, &self.header, cur_child_file_offset, false)?;
             cur_child_file_offset = child_file.sibling_file_offset;
         }
 
-        Ok(RomFsDirectoryIterator::new(self.reader.clone(), self.header.dir_table_offset, self.header.file_table_offset, child_dir_offsets, child_file_offsets))
+        Ok(RomFsDirectoryIterator::new(self.reader.clone(), self.header, self.decode_mode, child_dir_offsets, child_file_offsets))
     }
 
+    #[inline]
     pub fn new(reader: Shared<dyn ReadSeek>) -> Result<Self> {
-        let header: Header = reader_read_val(&reader)?;
+        Self::new_with_options(reader, DecodeMode::default())
+    }
+
+    #[inline]
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self> {
+        Self::new(new_shared(Cursor::new(data)))
+    }
+
+    pub fn new_with_options(reader: Shared<dyn ReadSeek>, decode_mode: DecodeMode) -> Result<Self> {
+        let header: Header = reader_read_val_at(&reader, 0)?;
+        // Lookups hash names into these tables, which therefore need at least one bucket
+        if (header.dir_hash_table_size < std::mem::size_of::<u32>()) || (header.file_hash_table_size < std::mem::size_of::<u32>()) {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Invalid RomFs hash table sizes (dir: {:#X}, file: {:#X})", header.dir_hash_table_size, header.file_hash_table_size)));
+        }
+
+        // There is no stored end for the file data, so the image end is the limit
+        let data_end_offset = reader.lock().unwrap().seek(SeekFrom::End(0))?;
 
         Ok(Self {
-            reader: reader,
-            header: header
+            reader,
+            header,
+            data_end_offset,
+            decode_mode,
+            index: None,
+            path_options: PathOptions::default()
         })
     }
-}
\ No newline at end of file
+}
+
+fn split_path(path: &str) -> (&str, &str) {
+    path.rsplit_once('/').unwrap_or(("", path))
+}
+
+fn write_padding<W: Write>(writer: &mut W, size: usize) -> Result<()> {
+    writer.write_all(&vec![0u8; size])
+}
+
+// Host files only get opened when the builder reads them, so that big directories don't keep every file open at once
+struct HostFileReader {
+    path: PathBuf,
+    file: Option<File>
+}
+
+impl Read for HostFileReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.file.is_none() {
+            self.file = Some(File::open(&self.path)?);
+        }
+
+        self.file.as_mut().unwrap().read(buf)
+    }
+}
+
+#[derive(Default)]
+pub struct RomFsBuilder {
+    files: BTreeMap<String, Box<dyn Read>>
+}
+
+impl RomFsBuilder {
+    const HEADER_REGION_SIZE: usize = 0x200;
+    const FILE_DATA_ALIGNMENT: usize = 0x10;
+    const TABLE_ALIGNMENT: usize = 0x4;
+
+    // Same bucket count rule Nintendo's (and every compatible) RomFs builder follows
+    fn get_hash_table_count(entry_count: usize) -> usize {
+        if entry_count < 3 {
+            3
+        }
+        else if entry_count < 19 {
+            entry_count | 1
+        }
+        else {
+            let mut count = entry_count;
+            while [2, 3, 5, 7, 11, 13, 17].iter().any(|prime| count.is_multiple_of(*prime)) {
+                count += 1;
+            }
+            count
+        }
+    }
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_file<R: Read + 'static>(&mut self, path: String, reader: R) -> Result<()> {
+        let path = path.trim_start_matches('/');
+        // "." and ".." would be stored as regular names, but they couldn't be looked up or extracted later
+        if path.split('/').any(|item| item.is_empty() || (item == ".") || (item == "..")) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid RomFs file path: '{}'", path)));
+        }
+        if self.files.contains_key(path) {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("RomFs file already added: '{}'", path)));
+        }
+
+        self.files.insert(String::from(path), Box::new(reader));
+        Ok(())
+    }
+
+    // Dir symlinks are followed too, ancestor_dirs (canonicalized) is what guards against symlink cycles
+    fn add_host_dir(&mut self, host_dir: &Path, dir_path: &str, ancestor_dirs: &mut Vec<PathBuf>) -> Result<()> {
+        let canonical_dir = host_dir.canonicalize()?;
+        if ancestor_dirs.contains(&canonical_dir) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Directory symlink cycle at '{}'", host_dir.display())));
+        }
+        ancestor_dirs.push(canonical_dir);
+
+        for dir_entry in read_dir(host_dir)? {
+            let dir_entry = dir_entry?;
+            let host_path = dir_entry.path();
+            let name = match dir_entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(name) => return Err(Error::new(ErrorKind::InvalidData, format!("Non-UTF-8 file name: {:?}", name)))
+            };
+            let path = format!("{}/{}", dir_path, name);
+
+            // Anything other than files and dirs is ignored
+            let metadata = std::fs::metadata(&host_path)?;
+            if metadata.is_dir() {
+                self.add_host_dir(&host_path, &path, ancestor_dirs)?;
+            }
+            else if metadata.is_file() {
+                self.add_file(path, HostFileReader { path: host_path, file: None })?;
+            }
+        }
+
+        ancestor_dirs.pop();
+        Ok(())
+    }
+
+    // Empty dirs aren't kept, since the builder only tracks files (and their parent dirs)
+    pub fn from_dir(dir: &Path) -> Result<Self> {
+        let mut builder = Self::new();
+        builder.add_host_dir(dir, "", &mut Vec::new())?;
+        Ok(builder)
+    }
+
+    // Layout (matching official images):
+    // - header, zero-padded to 0x200, followed by the file data (each file aligned to 0x10, in table order)
+    // - then, aligned to 0x4: dir hash table, dir table, file hash table, file table
+    // - dirs and files are both ordered by their full path (byte-wise), the root dir always being the first entry
+    // - hash chains are built in table order, each new entry becoming the head of its bucket
+    pub fn build<W: Write + Seek>(self, mut writer: W) -> Result<()> {
+        let base_offset = writer.stream_position()?;
+        let (file_paths, file_readers): (Vec<String>, Vec<Box<dyn Read>>) = self.files.into_iter().unzip();
+
+        let mut dir_paths: BTreeSet<&str> = BTreeSet::new();
+        dir_paths.insert("");
+        for file_path in file_paths.iter() {
+            let mut cur_path = file_path.as_str();
+            while let Some((parent_path, _)) = cur_path.rsplit_once('/') {
+                dir_paths.insert(parent_path);
+                cur_path = parent_path;
+            }
+        }
+        let dir_paths: Vec<&str> = dir_paths.into_iter().collect();
+
+        let mut dir_indices: BTreeMap<&str, usize> = BTreeMap::new();
+        let mut dirs: Vec<DirectoryInfo> = Vec::new();
+        let mut dir_offsets: Vec<u32> = Vec::new();
+        let mut dir_table_size: usize = 0;
+        for (i, dir_path) in dir_paths.iter().enumerate() {
+            let (_, name) = split_path(dir_path);
+            dir_indices.insert(dir_path, i);
+            dir_offsets.push(dir_table_size as u32);
+            dirs.push(DirectoryInfo {
+                parent_dir_offset: RomFs::ROOT_DIR_OFFSET,
+                sibling_dir_offset: RomFs::INVALID_INFO_OFFSET,
+                first_child_dir_offset: RomFs::INVALID_INFO_OFFSET,
+                first_child_file_offset: RomFs::INVALID_INFO_OFFSET,
+                next_dir_hash: RomFs::INVALID_INFO_OFFSET,
+                name_len: name.len() as u32
+            });
+            dir_table_size += std::mem::size_of::<DirectoryInfo>() + align_up(name.len(), Self::TABLE_ALIGNMENT);
+        }
+
+        let mut files: Vec<FileInfo> = Vec::new();
+        let mut file_offsets: Vec<u32> = Vec::new();
+        let mut file_table_size: usize = 0;
+        for file_path in file_paths.iter() {
+            let (_, name) = split_path(file_path);
+            file_offsets.push(file_table_size as u32);
+            files.push(FileInfo {
+                parent_dir_offset: RomFs::ROOT_DIR_OFFSET,
+                sibling_file_offset: RomFs::INVALID_INFO_OFFSET,
+                data_offset: 0,
+                data_size: 0,
+                next_file_hash: RomFs::INVALID_INFO_OFFSET,
+                name_len: name.len() as u32
+            });
+            file_table_size += std::mem::size_of::<FileInfo>() + align_up(name.len(), Self::TABLE_ALIGNMENT);
+        }
+
+        // Since entries are visited in path order, children get linked in name order
+        let mut dir_hash_table = vec![RomFs::INVALID_INFO_OFFSET; Self::get_hash_table_count(dirs.len())];
+        let mut last_child_dirs: Vec<Option<usize>> = vec![None; dirs.len()];
+        for (i, dir_path) in dir_paths.iter().enumerate() {
+            let (parent_path, name) = split_path(dir_path);
+            if i > 0 {
+                let parent_idx = dir_indices[parent_path];
+                dirs[i].parent_dir_offset = dir_offsets[parent_idx];
+                match last_child_dirs[parent_idx] {
+                    Some(sibling_idx) => dirs[sibling_idx].sibling_dir_offset = dir_offsets[i],
+                    None => dirs[parent_idx].first_child_dir_offset = dir_offsets[i]
+                };
+                last_child_dirs[parent_idx] = Some(i);
+            }
+
+            let hash = RomFs::compute_hash(dirs[i].parent_dir_offset, name.as_bytes(), dir_hash_table.len()) as usize;
+            dirs[i].next_dir_hash = dir_hash_table[hash];
+            dir_hash_table[hash] = dir_offsets[i];
+        }
+
+        let mut file_hash_table = vec![RomFs::INVALID_INFO_OFFSET; Self::get_hash_table_count(files.len())];
+        let mut last_child_files: Vec<Option<usize>> = vec![None; dirs.len()];
+        for (i, file_path) in file_paths.iter().enumerate() {
+            let (parent_path, name) = split_path(file_path);
+            let parent_idx = dir_indices[parent_path];
+            files[i].parent_dir_offset = dir_offsets[parent_idx];
+            match last_child_files[parent_idx] {
+                Some(sibling_idx) => files[sibling_idx].sibling_file_offset = file_offsets[i],
+                None => dirs[parent_idx].first_child_file_offset = file_offsets[i]
+            };
+            last_child_files[parent_idx] = Some(i);
+
+            let hash = RomFs::compute_hash(files[i].parent_dir_offset, name.as_bytes(), file_hash_table.len()) as usize;
+            files[i].next_file_hash = file_hash_table[hash];
+            file_hash_table[hash] = file_offsets[i];
+        }
+
+        write_padding(&mut writer, Self::HEADER_REGION_SIZE)?;
+
+        let mut file_data_size: usize = 0;
+        for (file, mut file_reader) in files.iter_mut().zip(file_readers) {
+            let data_offset = align_up(file_data_size, Self::FILE_DATA_ALIGNMENT);
+            write_padding(&mut writer, data_offset - file_data_size)?;
+
+            let data_size = std::io::copy(&mut file_reader, &mut writer)? as usize;
+            file.data_offset = data_offset as u64;
+            file.data_size = data_size;
+            file_data_size = data_offset + data_size;
+        }
+
+        let tables_offset = align_up(Self::HEADER_REGION_SIZE + file_data_size, Self::TABLE_ALIGNMENT);
+        write_padding(&mut writer, tables_offset - (Self::HEADER_REGION_SIZE + file_data_size))?;
+
+        let header = Header {
+            header_size: std::mem::size_of::<Header>(),
+            dir_hash_table_offset: tables_offset as u64,
+            dir_hash_table_size: dir_hash_table.len() * std::mem::size_of::<u32>(),
+            dir_table_offset: (tables_offset + dir_hash_table.len() * std::mem::size_of::<u32>()) as u64,
+            dir_table_size,
+            file_hash_table_offset: (tables_offset + dir_hash_table.len() * std::mem::size_of::<u32>() + dir_table_size) as u64,
+            file_hash_table_size: file_hash_table.len() * std::mem::size_of::<u32>(),
+            file_table_offset: (tables_offset + (dir_hash_table.len() + file_hash_table.len()) * std::mem::size_of::<u32>() + dir_table_size) as u64,
+            file_table_size,
+            file_data_offset: Self::HEADER_REGION_SIZE as u64
+        };
+
+        for hash_entry in dir_hash_table.iter() {
+            writer.write_all(&hash_entry.to_le_bytes())?;
+        }
+        for (dir, dir_path) in dirs.iter().zip(dir_paths.iter()) {
+            let (_, name) = split_path(dir_path);
+            writer_write_val(&mut writer, dir)?;
+            writer.write_all(name.as_bytes())?;
+            write_padding(&mut writer, align_up(name.len(), Self::TABLE_ALIGNMENT) - name.len())?;
+        }
+
+        for hash_entry in file_hash_table.iter() {
+            writer.write_all(&hash_entry.to_le_bytes())?;
+        }
+        for (file, file_path) in files.iter().zip(file_paths.iter()) {
+            let (_, name) = split_path(file_path);
+            writer_write_val(&mut writer, file)?;
+            writer.write_all(name.as_bytes())?;
+            write_padding(&mut writer, align_up(name.len(), Self::TABLE_ALIGNMENT) - name.len())?;
+        }
+
+        let end_offset = writer.stream_position()?;
+        writer.seek(SeekFrom::Start(base_offset))?;
+        writer_write_val(&mut writer, &header)?;
+        writer.seek(SeekFrom::Start(end_offset))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,68 @@
+use std::io::Result;
+use crate::romfs::RomFs;
+use crate::util::{DataReader, ReadSeek, Shared, new_shared, read_nul_terminated_str, reader_read_at};
+
+// The SystemVersion system data archive (a Data NCA with this program ID) has a RomFs with the "file" entry below
+pub const SYSTEM_VERSION_PROGRAM_ID: u64 = 0x0100000000000809;
+pub const SYSTEM_VERSION_FILE_SIZE: usize = 0x100;
+
+const PLATFORM_OFFSET: usize = 0x8;
+const PLATFORM_SIZE: usize = 0x20;
+const VERSION_HASH_OFFSET: usize = 0x28;
+const VERSION_HASH_SIZE: usize = 0x40;
+const DISPLAY_VERSION_OFFSET: usize = 0x68;
+const DISPLAY_VERSION_SIZE: usize = 0x18;
+const DISPLAY_TITLE_OFFSET: usize = 0x80;
+const DISPLAY_TITLE_SIZE: usize = 0x80;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SystemVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub micro: u8,
+    pub revision_major: u8,
+    pub revision_minor: u8,
+    pub platform: String,
+    pub version_hash: String,
+    pub display_version: String,
+    pub display_title: String,
+    // Only present in 5.0.0+
+    pub digest: Option<Vec<u8>>
+}
+
+impl SystemVersion {
+    pub fn new(reader: Shared<dyn ReadSeek>) -> Result<Self> {
+        let mut data = vec![0u8; SYSTEM_VERSION_FILE_SIZE];
+        reader_read_at(&reader, 0, &mut data)?;
+
+        Ok(Self {
+            major: data[0],
+            minor: data[1],
+            micro: data[2],
+            revision_major: data[4],
+            revision_minor: data[5],
+            platform: read_nul_terminated_str(&data[PLATFORM_OFFSET..PLATFORM_OFFSET + PLATFORM_SIZE]),
+            version_hash: read_nul_terminated_str(&data[VERSION_HASH_OFFSET..VERSION_HASH_OFFSET + VERSION_HASH_SIZE]),
+            display_version: read_nul_terminated_str(&data[DISPLAY_VERSION_OFFSET..DISPLAY_VERSION_OFFSET + DISPLAY_VERSION_SIZE]),
+            display_title: read_nul_terminated_str(&data[DISPLAY_TITLE_OFFSET..DISPLAY_TITLE_OFFSET + DISPLAY_TITLE_SIZE]),
+            digest: None
+        })
+    }
+
+    pub fn from_romfs(romfs: &mut RomFs) -> Result<Self> {
+        let data = romfs.read_range(String::from("file"), 0, SYSTEM_VERSION_FILE_SIZE)?;
+        let mut system_version = Self::new(new_shared(DataReader::new(data)))?;
+
+        if romfs.exists_file(String::from("digest")) {
+            let digest_size = romfs.get_file_size(String::from("digest"))?;
+            system_version.digest = Some(romfs.read_range(String::from("digest"), 0, digest_size)?);
+        }
+
+        Ok(system_version)
+    }
+
+    #[inline]
+    pub fn get_version(&self) -> (u8, u8, u8) {
+        (self.major, self.minor, self.micro)
+    }
+}
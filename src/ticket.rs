@@ -0,0 +1,157 @@
+use std::io::{Error, ErrorKind, Result};
+use aes::Aes128;
+use block_modes::{BlockMode, Ecb};
+use block_modes::block_padding::NoPadding;
+use crate::key::Keyset;
+use crate::util::{PlainData, ReadSeek, Shared, new_shared, reader_read_at, reader_read_val_at};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u32)]
+pub enum SignatureType {
+    Rsa4096Sha1 = 0x10000,
+    Rsa2048Sha1 = 0x10001,
+    EcdsaSha1 = 0x10002,
+    Rsa4096Sha256 = 0x10003,
+    Rsa2048Sha256 = 0x10004,
+    EcdsaSha256 = 0x10005
+}
+
+impl SignatureType {
+    pub fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            0x10000 => Some(Self::Rsa4096Sha1),
+            0x10001 => Some(Self::Rsa2048Sha1),
+            0x10002 => Some(Self::EcdsaSha1),
+            0x10003 => Some(Self::Rsa4096Sha256),
+            0x10004 => Some(Self::Rsa2048Sha256),
+            0x10005 => Some(Self::EcdsaSha256),
+            _ => None
+        }
+    }
+
+    #[inline]
+    pub fn get_signature_size(&self) -> usize {
+        match self {
+            Self::Rsa4096Sha1 | Self::Rsa4096Sha256 => 0x200,
+            Self::Rsa2048Sha1 | Self::Rsa2048Sha256 => 0x100,
+            Self::EcdsaSha1 | Self::EcdsaSha256 => 0x3C
+        }
+    }
+
+    // Signatures are padded so that the ticket data is 0x40-aligned
+    #[inline]
+    pub fn get_padding_size(&self) -> usize {
+        match self {
+            Self::Rsa4096Sha1 | Self::Rsa4096Sha256 | Self::Rsa2048Sha1 | Self::Rsa2048Sha256 => 0x3C,
+            Self::EcdsaSha1 | Self::EcdsaSha256 => 0x40
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum TitleKeyType {
+    Common = 0,
+    Personalized = 1
+}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct TicketData {
+    pub issuer: [u8; 0x40],
+    pub title_key_block: [u8; 0x100],
+    pub format_version: u8,
+    pub title_key_type: u8,
+    pub version: u16,
+    pub license_type: u8,
+    pub key_generation: u8,
+    pub property_mask: u16,
+    pub reserved: [u8; 0x8],
+    pub ticket_id: u64,
+    pub device_id: u64,
+    pub rights_id: [u8; 0x10],
+    pub account_id: u32,
+    pub section_records_total_size: u32,
+    pub section_records_offset: u32,
+    pub section_record_count: u16,
+    pub section_record_entry_size: u16
+}
+
+unsafe impl PlainData for TicketData {}
+
+impl TicketData {
+    #[inline]
+    pub fn get_title_key_type(&self) -> Option<TitleKeyType> {
+        match self.title_key_type {
+            0 => Some(TitleKeyType::Common),
+            1 => Some(TitleKeyType::Personalized),
+            _ => None
+        }
+    }
+
+    // Same as NCA key generations, both 0 and 1 are master key 0
+    #[inline]
+    pub fn get_key_generation(&self) -> u8 {
+        self.key_generation.saturating_sub(1)
+    }
+}
+
+pub struct Ticket {
+    pub signature_type: SignatureType,
+    pub signature: Vec<u8>,
+    pub data: TicketData
+}
+
+impl Ticket {
+    pub fn new(reader: Shared<dyn ReadSeek>) -> Result<Self> {
+        let signature_type_val: u32 = reader_read_val_at(&reader, 0)?;
+        let signature_type = match SignatureType::from_u32(signature_type_val) {
+            Some(signature_type) => signature_type,
+            None => return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid ticket signature type: {:#X}", signature_type_val)))
+        };
+
+        let mut signature = vec![0u8; signature_type.get_signature_size()];
+        reader_read_at(&reader, 0x4, &mut signature)?;
+        let data: TicketData = reader_read_val_at(&reader, (0x4 + signature.len() + signature_type.get_padding_size()) as u64)?;
+
+        Ok(Self {
+            signature_type,
+            signature,
+            data
+        })
+    }
+
+    #[inline]
+    pub fn from<R: ReadSeek + 'static>(reader: R) -> Result<Self> {
+        Self::new(new_shared(reader))
+    }
+
+    #[inline]
+    pub fn get_rights_id(&self) -> [u8; 0x10] {
+        self.data.rights_id
+    }
+
+    // This is the key to give to NCA::new/NCA::set_title_key or register in the keyset
+    pub fn get_encrypted_title_key(&self) -> Result<[u8; 0x10]> {
+        match self.data.get_title_key_type() {
+            Some(TitleKeyType::Common) => Ok(self.data.title_key_block[..0x10].try_into().unwrap()),
+            // The whole block is RSA-OAEP encrypted with the console's own (eticket) key
+            Some(TitleKeyType::Personalized) => Err(Error::new(ErrorKind::Unsupported, "Personalized ticket title keys are not supported")),
+            None => Err(Error::new(ErrorKind::InvalidData, format!("Invalid ticket title key type: {}", self.data.title_key_type)))
+        }
+    }
+
+    pub fn decrypt_title_key(&self, keyset: &Keyset) -> Result<[u8; 0x10]> {
+        let mut title_key = self.get_encrypted_title_key()?;
+
+        let key_gen = self.data.get_key_generation() as usize;
+        let title_key_encryption_key = match keyset.title_key_encryption_keys.get(key_gen) {
+            Some(title_key_encryption_key) => title_key_encryption_key,
+            None => return Err(Error::new(ErrorKind::InvalidInput, format!("Title key encryption key (titlekek_*) not present for key generation {}", key_gen)))
+        };
+
+        let title_key_ecb = Ecb::<Aes128, NoPadding>::new_var(title_key_encryption_key, &[0; 0x10]).unwrap();
+        title_key_ecb.decrypt(&mut title_key).unwrap();
+        Ok(title_key)
+    }
+}
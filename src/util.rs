@@ -1,16 +1,50 @@
-use std::io::{Read, Result, Seek, SeekFrom};
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use aes::Aes128;
+use aes::NewBlockCipher;
 use ctr::Ctr128;
-use ctr::cipher::NewStreamCipher;
+use ctr::cipher::stream::FromBlockCipher;
 use ctr::cipher::StreamCipher;
+use ctr::cipher::generic_array::GenericArray;
+use sha2::{Digest, Sha256};
+use xts_mode::Xts128;
 
+// The only sharing primitive used across the crate (readers are shared between containers, their files and possibly threads)
 pub type Shared<T> = Arc<Mutex<T>>;
 
 pub trait ReadSeek: Read + Seek + Send + Sync {}
 impl<R: Read + Seek + Send + Sync> ReadSeek for R {}
 
-pub fn reader_read_val<T>(reader: &Shared<dyn ReadSeek>) -> Result<T> {
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum DecodeMode {
+    Strict,
+    #[default]
+    Lossy
+}
+
+pub fn decode_name(name_data: Vec<u8>, decode_mode: DecodeMode) -> Result<String> {
+    match decode_mode {
+        // The lossy form is included so that the offending entry can still be identified
+        DecodeMode::Strict => String::from_utf8(name_data).map_err(|err| Error::new(ErrorKind::InvalidData, format!("Invalid UTF-8 name '{}': {}", String::from_utf8_lossy(err.as_bytes()), err.utf8_error()))),
+        DecodeMode::Lossy => Ok(String::from_utf8_lossy(&name_data).into_owned())
+    }
+}
+
+/// Raw values read and written in place
+///
+/// # Safety
+///
+/// Any bytes must make a valid value and every byte of a value must be initialized, so this is only for primitives, byte arrays and raw structs made of them (no enum fields or padding)
+pub unsafe trait PlainData: Copy {}
+
+unsafe impl PlainData for u8 {}
+unsafe impl PlainData for u16 {}
+unsafe impl PlainData for u32 {}
+unsafe impl PlainData for u64 {}
+unsafe impl<const N: usize> PlainData for [u8; N] {}
+
+pub fn reader_read_val<T: PlainData>(reader: &Shared<dyn ReadSeek>) -> Result<T> {
     let mut t: T = unsafe {
         std::mem::zeroed()
     };
@@ -23,6 +57,88 @@ pub fn reader_read_val<T>(reader: &Shared<dyn ReadSeek>) -> Result<T> {
     Ok(t)
 }
 
+// Seeks and reads under a single lock, so that it's safe even if the reader is being used from other threads
+// Short reads are retried until the whole buffer is filled, hitting EOF earlier is an error
+pub fn reader_read_at(reader: &Shared<dyn ReadSeek>, offset: u64, buf: &mut [u8]) -> Result<()> {
+    let mut reader = reader.lock().unwrap();
+    reader.seek(SeekFrom::Start(offset))?;
+
+    let mut read_size = 0;
+    while read_size < buf.len() {
+        match reader.read(&mut buf[read_size..]) {
+            Ok(0) => return Err(Error::new(ErrorKind::UnexpectedEof, format!("Only {:#X} of {:#X} bytes could be read at offset {:#X}", read_size, buf.len(), offset))),
+            Ok(cur_read_size) => read_size += cur_read_size,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        }
+    }
+
+    Ok(())
+}
+
+pub fn reader_read_val_at<T: PlainData>(reader: &Shared<dyn ReadSeek>, offset: u64) -> Result<T> {
+    let mut t: T = unsafe {
+        std::mem::zeroed()
+    };
+
+    let t_buf = unsafe {
+        std::slice::from_raw_parts_mut(&mut t as *mut _ as *mut u8, std::mem::size_of::<T>())
+    };
+    reader_read_at(reader, offset, t_buf)?;
+
+    Ok(t)
+}
+
+// Raw structs with enum fields can't be read in place: an invalid discriminant would already be UB, so the bytes are checked first
+pub trait ValidateRaw: Copy {
+    fn validate_raw(data: &[u8]) -> Result<()>;
+}
+
+#[inline]
+pub fn check_raw_enum<E: TryFrom<u8, Error = Error>>(data: &[u8], offset: usize) -> Result<()> {
+    E::try_from(data[offset]).map(|_| ())
+}
+
+pub fn read_validated_val<T: ValidateRaw>(data: &[u8]) -> Result<T> {
+    if data.len() < std::mem::size_of::<T>() {
+        return Err(Error::new(ErrorKind::UnexpectedEof, format!("Only {:#X} of {:#X} bytes are available", data.len(), std::mem::size_of::<T>())));
+    }
+
+    T::validate_raw(data)?;
+    Ok(unsafe {
+        std::ptr::read_unaligned(data.as_ptr() as *const T)
+    })
+}
+
+// Fixed-size string fields are NUL-padded, but may also fill the whole field
+pub fn read_nul_terminated_str(data: &[u8]) -> String {
+    let str_len = data.iter().position(|c| *c == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..str_len]).into_owned()
+}
+
+#[inline]
+pub fn read_u32_le(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[inline]
+pub fn read_u64_le(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+pub fn reader_read_validated_val_at<T: ValidateRaw>(reader: &Shared<dyn ReadSeek>, offset: u64) -> Result<T> {
+    let mut data = vec![0u8; std::mem::size_of::<T>()];
+    reader_read_at(reader, offset, &mut data)?;
+    read_validated_val(&data)
+}
+
+pub fn writer_write_val<T: PlainData, W: Write + ?Sized>(writer: &mut W, t: &T) -> Result<()> {
+    let t_buf = unsafe {
+        std::slice::from_raw_parts(t as *const _ as *const u8, std::mem::size_of::<T>())
+    };
+    writer.write_all(t_buf)
+}
+
 pub struct DataReader {
     offset: usize,
     data: Vec<u8>
@@ -32,36 +148,106 @@ impl DataReader {
     pub fn new(data: Vec<u8>) -> Self {
         Self {
             offset: 0,
-            data: data
+            data
         }
     }
 }
 
 impl Read for DataReader {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        // Seeking past the end is allowed, reading there just hits EOF
+        if self.offset >= self.data.len() {
+            return Ok(0);
+        }
+
         let end = std::cmp::min(self.data.len(), self.offset + buf.len());
         let size = end - self.offset;
-        buf.copy_from_slice(&self.data[self.offset..end]);
+        buf[..size].copy_from_slice(&self.data[self.offset..end]);
         self.offset = end;
         Ok(size)
     }
 }
 
+// Same semantics as std::io::Cursor: seeking past the end is fine, before the start is an error (and keeps the position)
 impl Seek for DataReader {
     fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
-        match pos {
-            SeekFrom::Current(pos_val) => {
-                let new_offset = self.offset as i64 + pos_val;
-                self.offset = new_offset as usize;
+        let new_offset = match pos {
+            SeekFrom::Start(start_pos) => usize::try_from(start_pos).ok(),
+            SeekFrom::Current(cur_pos) => self.offset.checked_add_signed(cur_pos as isize),
+            SeekFrom::End(end_pos) => self.data.len().checked_add_signed(end_pos as isize)
+        };
+
+        match new_offset {
+            Some(new_offset) => {
+                self.offset = new_offset;
+                Ok(self.offset as u64)
             },
-            SeekFrom::Start(pos_val) => self.offset = pos_val as usize,
-            SeekFrom::End(pos_val) => {
-                let new_offset = self.data.len() as i64 + pos_val;
-                self.offset = new_offset as usize;
-            }
+            None => Err(Error::new(ErrorKind::InvalidInput, "Invalid seek to a negative or overflowing position"))
+        }
+    }
+}
+
+// Restricts a reader to [base_offset, base_offset + size): positions (including SeekFrom::End) are relative to that region and reads stop at its end
+pub struct SubReader {
+    base_reader: Shared<dyn ReadSeek>,
+    base_offset: u64,
+    size: u64,
+    offset: u64
+}
+
+impl SubReader {
+    pub fn new(base_reader: Shared<dyn ReadSeek>, base_offset: u64, size: u64) -> Self {
+        Self {
+            base_reader,
+            base_offset,
+            size,
+            offset: 0
+        }
+    }
+
+    #[inline]
+    pub fn get_size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl Read for SubReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let remaining_size = self.size.saturating_sub(self.offset);
+        let read_size = std::cmp::min(buf.len() as u64, remaining_size) as usize;
+        if read_size == 0 {
+            return Ok(0);
+        }
+
+        let read_offset = self.base_offset + self.offset;
+        let mut base_reader = self.base_reader.lock().unwrap();
+
+        // Only reposition the base reader for non-contiguous reads (someone else might have used it meanwhile)
+        if base_reader.stream_position()? != read_offset {
+            base_reader.seek(SeekFrom::Start(read_offset))?;
+        }
+
+        let read_size = base_reader.read(&mut buf[..read_size])?;
+        self.offset += read_size as u64;
+        Ok(read_size)
+    }
+}
+
+impl Seek for SubReader {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let new_offset = match pos {
+            SeekFrom::Start(start_pos) => Some(start_pos),
+            SeekFrom::Current(cur_pos) => self.offset.checked_add_signed(cur_pos),
+            SeekFrom::End(end_pos) => self.size.checked_add_signed(end_pos)
         };
 
-        Ok(self.offset as u64)
+        match new_offset {
+            Some(new_offset) => {
+                self.offset = new_offset;
+                Ok(self.offset)
+            },
+            None => Err(Error::new(ErrorKind::InvalidInput, "Invalid seek to a negative or overflowing position"))
+        }
     }
 }
 
@@ -69,25 +255,117 @@ pub fn get_nintendo_tweak(sector_index: u128) -> [u8; 0x10] {
     sector_index.to_be_bytes()
 }
 
+// Same offset handling as the AES readers, for sections stored as plaintext
+pub struct PlainReader {
+    base_offset: u64,
+    size: u64,
+    offset: u64,
+    base_reader: Shared<dyn ReadSeek>
+}
+
+impl PlainReader {
+    pub fn new(base_reader: Shared<dyn ReadSeek>, base_offset: u64, size: u64) -> Self {
+        Self {
+            base_offset,
+            size,
+            offset: base_offset,
+            base_reader
+        }
+    }
+}
+
+impl Read for PlainReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let remaining_size = (self.base_offset + self.size).saturating_sub(self.offset);
+        let read_len = std::cmp::min(buf.len() as u64, remaining_size) as usize;
+        if read_len == 0 {
+            return Ok(0);
+        }
+
+        let mut base_reader = self.base_reader.lock().unwrap();
+        base_reader.seek(SeekFrom::Start(self.offset))?;
+        let read_size = base_reader.read(&mut buf[..read_len])?;
+        self.offset += read_size as u64;
+        Ok(read_size)
+    }
+}
+
+impl Seek for PlainReader {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let new_offset = match pos {
+            SeekFrom::Start(start_pos) => self.base_offset.checked_add(start_pos),
+            SeekFrom::Current(cur_pos) => self.offset.checked_add_signed(cur_pos),
+            SeekFrom::End(end_pos) => (self.base_offset + self.size).checked_add_signed(end_pos)
+        };
+
+        match new_offset {
+            Some(new_offset) if new_offset >= self.base_offset => {
+                self.offset = new_offset;
+                Ok(self.offset - self.base_offset)
+            },
+            _ => Err(Error::new(ErrorKind::InvalidInput, "Invalid seek to a negative or overflowing position"))
+        }
+    }
+}
+
+pub const AES_CTR_CACHE_SECTOR_SIZE: u64 = 0x200;
+pub const AES_CTR_CACHE_SECTOR_COUNT: usize = 8;
+
 pub struct Aes128CtrReader {
     base_offset: u64,
+    size: u64,
     offset: u64,
     base_reader: Shared<dyn ReadSeek>,
     ctr: u64,
-    key: Vec<u8>
+    // The key schedule is only expanded once, reads just create a new counter on top of a copy of it
+    cipher: Aes128,
+    // Most recently decrypted sectors (aligned offset, data), the newest one last
+    sector_cache: Vec<(u64, Vec<u8>)>
 }
 
 impl Aes128CtrReader {
-    pub fn new(base_reader: Shared<dyn ReadSeek>, base_offset: u64, ctr: u64, key: Vec<u8>) -> Self {
-        base_reader.lock().unwrap().seek(SeekFrom::Start(base_offset)).unwrap();
+    pub fn new(base_reader: Shared<dyn ReadSeek>, base_offset: u64, size: u64, ctr: u64, key: Vec<u8>) -> Self {
         Self {
-            base_offset: base_offset,
+            base_offset,
+            size,
             offset: base_offset,
-            base_reader: base_reader,
-            ctr: ctr,
-            key: key
+            base_reader,
+            ctr,
+            cipher: Aes128::new_varkey(&key).unwrap(),
+            sector_cache: Vec::with_capacity(AES_CTR_CACHE_SECTOR_COUNT)
+        }
+    }
+
+    // Cached sectors were decrypted with the previous counter, so they're dropped
+    pub fn set_ctr(&mut self, ctr: u64) {
+        if self.ctr != ctr {
+            self.ctr = ctr;
+            self.sector_cache.clear();
         }
     }
+
+    fn read_cached(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let sector_offset = align_down(self.offset, AES_CTR_CACHE_SECTOR_SIZE);
+        let sector_diff = (self.offset - sector_offset) as usize;
+        let (_, sector_data) = self.sector_cache.iter().find(|(cached_offset, _)| *cached_offset == sector_offset)?;
+
+        let copy_size = std::cmp::min(buf.len(), sector_data.len().saturating_sub(sector_diff));
+        if copy_size == 0 {
+            return None;
+        }
+
+        buf[..copy_size].copy_from_slice(&sector_data[sector_diff..sector_diff + copy_size]);
+        self.offset += copy_size as u64;
+        Some(copy_size)
+    }
+
+    fn cache_sector(&mut self, sector_offset: u64, sector_data: &[u8]) {
+        self.sector_cache.retain(|(cached_offset, _)| *cached_offset != sector_offset);
+        if self.sector_cache.len() >= AES_CTR_CACHE_SECTOR_COUNT {
+            self.sector_cache.remove(0);
+        }
+        self.sector_cache.push((sector_offset, sector_data.to_vec()));
+    }
 }
 
 pub const fn align_down(value: u64, align: u64) -> u64 {
@@ -102,46 +380,210 @@ pub const fn align_up(value: usize, align: usize) -> usize {
 
 impl Read for Aes128CtrReader {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        let offset = self.base_reader.lock().unwrap().stream_position()?;
-        let aligned_offset = align_down(offset, 0x10);
-        let diff = (offset - aligned_offset) as i64;
+        // Never read past the end of the encrypted region
+        let remaining_size = (self.base_offset + self.size).saturating_sub(self.offset);
+        let read_len = std::cmp::min(buf.len() as u64, remaining_size) as usize;
+        if read_len == 0 {
+            return Ok(0);
+        }
+        let buf = &mut buf[..read_len];
+
+        // Sequential small reads (headers, tables...) usually land in the sector decrypted by the previous read
+        if let Some(cached_size) = self.read_cached(buf) {
+            return Ok(cached_size);
+        }
+
+        let offset = self.offset;
+        let aligned_offset = align_down(offset, AES_CTR_CACHE_SECTOR_SIZE);
+        let diff = (offset - aligned_offset) as usize;
 
-        let read_buf_size_raw = buf.len() + diff as usize;
-        let read_buf_size = align_up(read_buf_size_raw, 0x10);
-        let read_buf_size_diff = (read_buf_size - read_buf_size_raw) as i64;
+        let read_buf_size = align_up(buf.len() + diff, AES_CTR_CACHE_SECTOR_SIZE as usize);
         let mut read_buf = vec![0u8; read_buf_size];
-        self.seek(SeekFrom::Current(-diff))?;
-        let read_size = self.base_reader.lock().unwrap().read(&mut read_buf)? as i64;
-        self.seek(SeekFrom::Current(read_size - read_buf_size_diff))?;
+        let mut base_read_size = 0;
+        {
+            let mut base_reader = self.base_reader.lock().unwrap();
+            base_reader.seek(SeekFrom::Start(aligned_offset))?;
+            while base_read_size < read_buf_size {
+                match base_reader.read(&mut read_buf[base_read_size..]) {
+                    Ok(0) => break,
+                    Ok(cur_read_size) => base_read_size += cur_read_size,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e)
+                }
+            }
+        }
+
+        // The base reader may end before the region does (truncated images), only actually read bytes are returned
+        let dec_size = std::cmp::min(base_read_size.saturating_sub(diff), buf.len());
+        self.offset += dec_size as u64;
 
         let iv = get_nintendo_tweak(((aligned_offset as u128) >> 4) | ((self.ctr as u128) << 64));
-        let mut ctr = Ctr128::<Aes128>::new_var(&self.key, &iv).unwrap();
+        let mut ctr = Ctr128::<Aes128>::from_block_cipher(self.cipher.clone(), GenericArray::from_slice(&iv));
         ctr.decrypt(&mut read_buf);
 
-        let read_buf_start = diff as usize;
-        let read_buf_end = read_buf_start + buf.len();
-        buf.copy_from_slice(&read_buf[read_buf_start..read_buf_end]);
+        buf[..dec_size].copy_from_slice(&read_buf[diff..diff + dec_size]);
 
-        Ok(buf.len())
+        // Only the last sector is kept, caching whole large reads would just waste memory
+        let last_sector_start = align_down(((diff + dec_size) as u64).saturating_sub(1), AES_CTR_CACHE_SECTOR_SIZE) as usize;
+        if last_sector_start < base_read_size {
+            let last_sector_end = std::cmp::min(last_sector_start + AES_CTR_CACHE_SECTOR_SIZE as usize, base_read_size);
+            self.cache_sector(aligned_offset + last_sector_start as u64, &read_buf[last_sector_start..last_sector_end]);
+        }
+
+        Ok(dec_size)
     }
 }
 
+// Positions are relative to the start of the encrypted region (base_offset), which can't be seeked before
 impl Seek for Aes128CtrReader {
     fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
-        match pos {
-            SeekFrom::Current(cur_pos) => {
-                let new_offset = self.offset as i64 + cur_pos;
-                self.offset = new_offset as u64;
+        let new_offset = match pos {
+            SeekFrom::Start(start_pos) => self.base_offset.checked_add(start_pos),
+            SeekFrom::Current(cur_pos) => self.offset.checked_add_signed(cur_pos),
+            SeekFrom::End(end_pos) => (self.base_offset + self.size).checked_add_signed(end_pos)
+        };
+
+        // The base reader is only positioned when actually reading
+        match new_offset {
+            Some(new_offset) if new_offset >= self.base_offset => {
+                self.offset = new_offset;
+                Ok(self.offset - self.base_offset)
             },
-            SeekFrom::Start(start_pos) => self.offset = self.base_offset + start_pos,
-            SeekFrom::End(end_pos) => {
-                let new_offset = self.offset as i64 + end_pos;
-                self.offset = new_offset as u64;
-            }
+            _ => Err(Error::new(ErrorKind::InvalidInput, "Invalid seek to a negative or overflowing position"))
         }
+    }
+}
+
+pub struct Aes128XtsReader {
+    base_offset: u64,
+    size: u64,
+    offset: u64,
+    base_reader: Shared<dyn ReadSeek>,
+    xts: Xts128<Aes128>
+}
+
+impl Aes128XtsReader {
+    pub const SECTOR_SIZE: usize = 0x200;
+
+    pub fn new(base_reader: Shared<dyn ReadSeek>, base_offset: u64, size: u64, key: Vec<u8>) -> Self {
+        let cipher_1 = Aes128::new_varkey(&key[..0x10]).unwrap();
+        let cipher_2 = Aes128::new_varkey(&key[0x10..0x20]).unwrap();
+        Self {
+            base_offset,
+            size,
+            offset: base_offset,
+            base_reader,
+            xts: Xts128::new(cipher_1, cipher_2)
+        }
+    }
+}
+
+impl Read for Aes128XtsReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let remaining_size = (self.base_offset + self.size).saturating_sub(self.offset);
+        let read_len = std::cmp::min(buf.len() as u64, remaining_size) as usize;
+        if read_len == 0 {
+            return Ok(0);
+        }
+        let buf = &mut buf[..read_len];
+
+        // Sectors are numbered starting from the beginning of the encrypted region (seeking never goes before it)
+        let rel_offset = self.offset - self.base_offset;
+        let aligned_rel_offset = align_down(rel_offset, Self::SECTOR_SIZE as u64);
+        let diff = (rel_offset - aligned_rel_offset) as usize;
+
+        let mut read_buf = vec![0u8; align_up(buf.len() + diff, Self::SECTOR_SIZE)];
+        {
+            let mut base_reader = self.base_reader.lock().unwrap();
+            base_reader.seek(SeekFrom::Start(self.base_offset + aligned_rel_offset))?;
+            base_reader.read_exact(&mut read_buf)?;
+        }
+        self.xts.decrypt_area(&mut read_buf, Self::SECTOR_SIZE, (aligned_rel_offset / Self::SECTOR_SIZE as u64) as u128, get_nintendo_tweak);
+
+        buf.copy_from_slice(&read_buf[diff..diff + buf.len()]);
+        self.offset += buf.len() as u64;
+        Ok(buf.len())
+    }
+}
+
+impl Seek for Aes128XtsReader {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let new_offset = match pos {
+            SeekFrom::Start(start_pos) => self.base_offset.checked_add(start_pos),
+            SeekFrom::Current(cur_pos) => self.offset.checked_add_signed(cur_pos),
+            SeekFrom::End(end_pos) => (self.base_offset + self.size).checked_add_signed(end_pos)
+        };
+
+        match new_offset {
+            Some(new_offset) if new_offset >= self.base_offset => {
+                self.offset = new_offset;
+                Ok(self.offset - self.base_offset)
+            },
+            _ => Err(Error::new(ErrorKind::InvalidInput, "Invalid seek to a negative or overflowing position"))
+        }
+    }
+}
+
+pub const DEFAULT_COPY_CHUNK_SIZE: usize = 0x100000;
+
+// Copies up to len bytes (less if the source ends earlier), reporting the total copied so far after each chunk
+pub fn copy_ranged<R: Read + ?Sized, W: Write + ?Sized>(src: &mut R, dst: &mut W, len: u64, chunk_size: usize, mut progress: Option<&mut dyn FnMut(u64)>) -> Result<u64> {
+    if chunk_size == 0 {
+        return Err(Error::new(ErrorKind::InvalidInput, "Invalid chunk size"));
+    }
+
+    let mut chunk = vec![0u8; std::cmp::min(chunk_size as u64, len) as usize];
+    let mut copied_size: u64 = 0;
+    while copied_size < len {
+        let read_size = std::cmp::min(chunk.len() as u64, len - copied_size) as usize;
+        let read_size = match src.read(&mut chunk[..read_size]) {
+            Ok(0) => break,
+            Ok(read_size) => read_size,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        };
+
+        dst.write_all(&chunk[..read_size])?;
+        copied_size += read_size as u64;
+        if let Some(progress) = progress.as_mut() {
+            progress(copied_size);
+        }
+    }
+
+    Ok(copied_size)
+}
+
+// Streams the whole reader through SHA256, so large files/sections can be hashed without reading them into memory
+pub fn hash_reader<R: Read + ?Sized>(reader: &mut R) -> Result<[u8; 0x20]> {
+    let mut hasher = Sha256::new();
+    copy_ranged(reader, &mut hasher, u64::MAX, DEFAULT_COPY_CHUNK_SIZE, None)?;
+    Ok(hasher.finalize().into())
+}
+
+// Member names come from the (untrusted) container, so they must not escape the output dir
+pub fn get_member_out_path(out_dir: &Path, name: &str) -> Result<PathBuf> {
+    let name_path = Path::new(name);
+    if name.is_empty() || !name_path.components().all(|component| matches!(component, Component::Normal(_))) {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Invalid member name for extraction: '{}'", name)));
+    }
+
+    Ok(out_dir.join(name_path))
+}
+
+pub fn format_size(size: u64) -> String {
+    const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+
+    if size < 1024 {
+        return format!("{} B", size);
+    }
 
-        self.base_reader.lock().unwrap().seek(SeekFrom::Start(self.offset))
+    let mut unit_size = size as f64 / 1024.0;
+    let mut unit_idx = 0;
+    while (unit_size >= 1024.0) && (unit_idx < UNITS.len() - 1) {
+        unit_size /= 1024.0;
+        unit_idx += 1;
     }
+    format!("{:.2} {}", unit_size, UNITS[unit_idx])
 }
 
 #[inline]
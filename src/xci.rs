@@ -0,0 +1,128 @@
+use std::io::{Error, ErrorKind, Result, SeekFrom};
+use crate::hfs0::HFS0;
+use crate::util::{PlainData, ReadSeek, Shared, SubReader, new_shared, reader_read_val_at};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(C)]
+pub struct Header {
+    pub header_signature_1: [u8; 0x80],
+    pub header_signature_2: [u8; 0x80],
+    pub magic: u32,
+    pub rom_area_start_page: u32,
+    pub backup_area_start_page: u32,
+    pub key_index: u8,
+    pub rom_size: u8,
+    pub header_version: u8,
+    pub flags: u8,
+    pub package_id: u64,
+    pub valid_data_end_page: u32,
+    pub reserved: [u8; 0x4],
+    pub iv: [u8; 0x10],
+    pub partition_fs_header_address: u64,
+    pub partition_fs_header_size: usize,
+    pub partition_fs_header_hash: [u8; 0x20],
+    pub initial_data_hash: [u8; 0x20],
+    pub sel_sec: u32,
+    pub sel_t1_key: u32,
+    pub sel_key: u32,
+    pub lim_area_page: u32,
+    pub encrypted_card_info_1: [u8; 0x40],
+    pub encrypted_card_info_2: [u8; 0x30]
+}
+
+unsafe impl PlainData for Header {}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum CartridgeSize {
+    Size1GB = 0xFA,
+    Size2GB = 0xF8,
+    Size4GB = 0xF0,
+    Size8GB = 0xE0,
+    Size16GB = 0xE1,
+    Size32GB = 0xE2
+}
+
+impl CartridgeSize {
+    // Nominal size, in bytes
+    pub fn get_size(self) -> u64 {
+        let size_gb: u64 = match self {
+            Self::Size1GB => 1,
+            Self::Size2GB => 2,
+            Self::Size4GB => 4,
+            Self::Size8GB => 8,
+            Self::Size16GB => 16,
+            Self::Size32GB => 32
+        };
+        size_gb << 30
+    }
+}
+
+impl Header {
+    pub const MAGIC: u32 = u32::from_le_bytes(*b"HEAD");
+
+    // The raw rom_size field is kept as-is since unknown values may be found on dumps
+    pub fn get_cartridge_size(&self) -> Option<CartridgeSize> {
+        match self.rom_size {
+            0xFA => Some(CartridgeSize::Size1GB),
+            0xF8 => Some(CartridgeSize::Size2GB),
+            0xF0 => Some(CartridgeSize::Size4GB),
+            0xE0 => Some(CartridgeSize::Size8GB),
+            0xE1 => Some(CartridgeSize::Size16GB),
+            0xE2 => Some(CartridgeSize::Size32GB),
+            _ => None
+        }
+    }
+}
+
+pub struct XCI {
+    pub header: Header,
+    root_partition: HFS0
+}
+
+impl XCI {
+    pub fn new(reader: Shared<dyn ReadSeek>) -> Result<Self> {
+        let header: Header = reader_read_val_at(&reader, 0)?;
+        if header.magic != Header::MAGIC {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid XCI magic"));
+        }
+
+        // The root partition's contents span until the end of the image
+        let image_size = reader.lock().unwrap().seek(SeekFrom::End(0))?;
+        let root_size = image_size.saturating_sub(header.partition_fs_header_address);
+        let root_reader = new_shared(SubReader::new(reader, header.partition_fs_header_address, root_size));
+
+        Ok(Self {
+            header,
+            root_partition: HFS0::new(root_reader)?
+        })
+    }
+
+    #[inline]
+    pub fn get_package_id(&self) -> u64 {
+        self.header.package_id
+    }
+
+    #[inline]
+    pub fn get_cartridge_size(&self) -> Option<CartridgeSize> {
+        self.header.get_cartridge_size()
+    }
+
+    #[inline]
+    pub fn partition_names(&self) -> Result<Vec<String>> {
+        self.root_partition.list_files()
+    }
+
+    pub fn open_partition(&mut self, name: &str) -> Result<HFS0> {
+        let partition_idx = match self.partition_names()?.iter().position(|partition_name| partition_name == name) {
+            Some(idx) => idx,
+            None => return Err(Error::new(ErrorKind::NotFound, format!("XCI has no '{}' partition", name)))
+        };
+
+        if !self.root_partition.verify_file(partition_idx)? {
+            return Err(Error::new(ErrorKind::InvalidData, format!("XCI '{}' partition hash mismatch", name)));
+        }
+
+        HFS0::new(new_shared(self.root_partition.open_file(partition_idx)?))
+    }
+}